@@ -1,28 +1,51 @@
+use std::{env, time::Instant};
+
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
     net::TcpStream,
 };
 
+/// Usage: load [clients] [ops_per_client] [addr] [set|get]
+///
+/// Run it once against a server started with each `wal.fsync` setting
+/// (`always`, `everysec`, `none`) to compare write throughput. `get` reads
+/// back the keys a `set` run with the same counts wrote, to measure reads.
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = env::args().collect();
+    let clients: usize = args.get(1).and_then(|a| a.parse().ok()).unwrap_or(50);
+    let ops_per_client: usize = args.get(2).and_then(|a| a.parse().ok()).unwrap_or(100);
+    let addr = args
+        .get(3)
+        .cloned()
+        .unwrap_or_else(|| "127.0.0.1:4000".to_string());
+    let reads = args
+        .get(4)
+        .is_some_and(|mode| mode.eq_ignore_ascii_case("get"));
+    let op_name = if reads { "GET" } else { "SET" };
+
     let mut tasks = vec![];
+    let started = Instant::now();
 
-    for i in 0..50 {
+    for i in 0..clients {
+        let addr = addr.clone();
         tasks.push(tokio::spawn(async move {
-            let mut stream = TcpStream::connect("127.0.0.1:4000")
-                .await
-                .expect("Failed to connect");
+            let stream = TcpStream::connect(&addr).await.expect("Failed to connect");
             let (reader, mut writer) = stream.into_split();
             let mut reader = BufReader::new(reader);
 
-            for j in 0..100 {
+            for j in 0..ops_per_client {
                 let key = format!("key_{}_{}", i, j);
                 let val = format!("val_{}_{}", i, j);
-                let cmd = format!("SET {} {}\n", key, val);
+                let cmd = if reads {
+                    format!("GET {}\n", key)
+                } else {
+                    format!("SET {} {}\n", key, val)
+                };
                 writer.write_all(cmd.as_bytes()).await.unwrap();
 
                 let mut resp = String::new();
-                if let Ok(_) = reader.read_line(&mut resp).await {
+                if reader.read_line(&mut resp).await.is_ok() {
                     // Optionally print every N responses
                     if j % 50 == 0 {
                         println!("Client {i} got response: {}", resp.trim());
@@ -38,5 +61,16 @@ async fn main() {
         let _ = t.await;
     }
 
+    let elapsed = started.elapsed();
+    let total_ops = clients * ops_per_client;
+
     println!("All clients done");
+    println!(
+        "{} {}s from {} clients in {:.2?} ({:.0} ops/sec)",
+        total_ops,
+        op_name,
+        clients,
+        elapsed,
+        total_ops as f64 / elapsed.as_secs_f64()
+    );
 }
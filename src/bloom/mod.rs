@@ -0,0 +1,86 @@
+#[cfg(test)]
+mod tests;
+
+/// Default number of filter bits per key, about a 1% false positive rate.
+pub const DEFAULT_BITS_PER_KEY: usize = 10;
+
+/// Bloom filter over the keys of one SSTable.
+///
+/// Probes use double hashing of a 64-bit FNV-1a hash, so the filter is stable
+/// on disk across builds. Encoded as the bit array followed by one byte with
+/// the number of probes.
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_probes: u8,
+}
+
+impl BloomFilter {
+    pub fn new(num_keys: usize, bits_per_key: usize) -> Self {
+        // ln(2) * bits_per_key probes minimises the false positive rate
+        let num_probes = ((bits_per_key as f64) * 0.69).round().clamp(1.0, 30.0) as u8;
+        let num_bits = (num_keys * bits_per_key).max(64);
+
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(8)],
+            num_probes,
+        }
+    }
+
+    pub fn add(&mut self, key: &[u8]) {
+        self.add_hash(key_hash(key));
+    }
+
+    /// Adds a key by its `key_hash`, for writers that don't keep the keys.
+    pub fn add_hash(&mut self, hash: u64) {
+        let num_bits = self.bits.len() * 8;
+        for bit in probes(hash, self.num_probes, num_bits) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// False means the key is definitely not in the table.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let num_bits = self.bits.len() * 8;
+        probes(key_hash(key), self.num_probes, num_bits)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Bytes held in memory (and on disk) by the filter.
+    pub fn size_bytes(&self) -> usize {
+        self.bits.len() + 1
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.bits.len() + 1);
+        buf.extend_from_slice(&self.bits);
+        buf.push(self.num_probes);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let (num_probes, bits) = buf.split_last()?;
+        if bits.is_empty() || *num_probes == 0 {
+            return None;
+        }
+        Some(BloomFilter {
+            bits: bits.to_vec(),
+            num_probes: *num_probes,
+        })
+    }
+}
+
+fn probes(hash: u64, num_probes: u8, num_bits: usize) -> impl Iterator<Item = usize> {
+    let h1 = hash as u32 as usize;
+    let h2 = (hash >> 32) as u32 as usize;
+    (0..num_probes as usize).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+}
+
+/// 64-bit FNV-1a hash of a key, which all probes are derived from.
+pub fn key_hash(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
@@ -0,0 +1,43 @@
+use crate::bloom::{BloomFilter, DEFAULT_BITS_PER_KEY};
+
+fn filter(keys: u32, bits_per_key: usize) -> BloomFilter {
+    let mut filter = BloomFilter::new(keys as usize, bits_per_key);
+    for i in 0..keys {
+        filter.add(format!("key{:06}", i).as_bytes());
+    }
+    filter
+}
+
+#[test]
+fn added_keys_are_always_found() {
+    for bits_per_key in [1, 4, DEFAULT_BITS_PER_KEY, 20] {
+        let filter = filter(10_000, bits_per_key);
+        for i in 0..10_000 {
+            let key = format!("key{:06}", i);
+            assert!(filter.may_contain(key.as_bytes()), "{}", key);
+        }
+    }
+
+    // Including after a round trip through the on-disk encoding
+    let filter = BloomFilter::decode(&filter(1000, DEFAULT_BITS_PER_KEY).encode()).unwrap();
+    for i in 0..1000 {
+        assert!(filter.may_contain(format!("key{:06}", i).as_bytes()));
+    }
+}
+
+#[test]
+fn most_other_keys_are_ruled_out() {
+    let filter = filter(10_000, DEFAULT_BITS_PER_KEY);
+    let false_positives = (0..10_000)
+        .filter(|i| filter.may_contain(format!("other{:06}", i).as_bytes()))
+        .count();
+    // About 1% at 10 bits per key
+    assert!(false_positives < 300, "{} false positives", false_positives);
+}
+
+#[test]
+fn malformed_filters_are_not_decoded() {
+    assert!(BloomFilter::decode(&[]).is_none());
+    assert!(BloomFilter::decode(&[7]).is_none());
+    assert!(BloomFilter::decode(&[0xff, 0]).is_none());
+}
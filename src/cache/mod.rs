@@ -0,0 +1,195 @@
+#[cfg(test)]
+mod tests;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use crate::{common::db_errors::DbError, ende::table::Table};
+
+/// Default capacity of the block cache.
+pub const DEFAULT_BLOCK_CACHE_BYTES: usize = 8 * 1024 * 1024;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum CacheKey {
+    /// Parsed header, footer, index and bloom filter of an SSTable.
+    Table(String),
+    /// Data block of an SSTable at an offset.
+    Block(String, u64),
+}
+
+impl CacheKey {
+    fn sstable_id(&self) -> &str {
+        match self {
+            CacheKey::Table(id) | CacheKey::Block(id, _) => id,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum CacheValue {
+    Table(Arc<Table>),
+    Block(Arc<Vec<u8>>),
+}
+
+/// LRU cache of SSTable indexes and data blocks shared by all readers, bounded
+/// by the approximate number of bytes it holds. Keyed by SSTable id (file name).
+pub struct BlockCache {
+    lru: Mutex<Lru<CacheKey, CacheValue>>,
+    index_hits: AtomicU64,
+    index_misses: AtomicU64,
+    block_hits: AtomicU64,
+    block_misses: AtomicU64,
+}
+
+impl BlockCache {
+    pub fn new(capacity_bytes: usize) -> Self {
+        BlockCache {
+            lru: Mutex::new(Lru::new(capacity_bytes)),
+            index_hits: AtomicU64::new(0),
+            index_misses: AtomicU64::new(0),
+            block_hits: AtomicU64::new(0),
+            block_misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached table, or opens it with `load` and caches it.
+    pub fn table<F>(&self, sstable_id: &str, load: F) -> Result<Arc<Table>, DbError>
+    where
+        F: FnOnce() -> Result<Table, DbError>,
+    {
+        let key = CacheKey::Table(sstable_id.to_string());
+        if let Some(CacheValue::Table(table)) = self.lru.lock().unwrap().get(&key) {
+            self.index_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(table);
+        }
+        self.index_misses.fetch_add(1, Ordering::Relaxed);
+
+        let table = Arc::new(load()?);
+        let size = table.size_bytes();
+        self.lru
+            .lock()
+            .unwrap()
+            .insert(key, CacheValue::Table(table.clone()), size);
+        Ok(table)
+    }
+
+    /// Returns the cached block at `offset`, or reads it with `load` and caches it.
+    pub fn block<F>(&self, sstable_id: &str, offset: u64, load: F) -> Result<Arc<Vec<u8>>, DbError>
+    where
+        F: FnOnce() -> Result<Vec<u8>, DbError>,
+    {
+        let key = CacheKey::Block(sstable_id.to_string(), offset);
+        if let Some(CacheValue::Block(block)) = self.lru.lock().unwrap().get(&key) {
+            self.block_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(block);
+        }
+        self.block_misses.fetch_add(1, Ordering::Relaxed);
+
+        let block = Arc::new(load()?);
+        let size = block.len();
+        self.lru
+            .lock()
+            .unwrap()
+            .insert(key, CacheValue::Block(block.clone()), size);
+        Ok(block)
+    }
+
+    /// Drops everything cached for an SSTable, e.g. once compaction deletes it.
+    pub fn invalidate(&self, sstable_id: &str) {
+        self.lru
+            .lock()
+            .unwrap()
+            .retain(|key| key.sstable_id() != sstable_id);
+    }
+
+    pub fn stats(&self) -> Vec<(&'static str, u64)> {
+        let lru = self.lru.lock().unwrap();
+        vec![
+            ("index_cache_hits", self.index_hits.load(Ordering::Relaxed)),
+            (
+                "index_cache_misses",
+                self.index_misses.load(Ordering::Relaxed),
+            ),
+            ("block_cache_hits", self.block_hits.load(Ordering::Relaxed)),
+            (
+                "block_cache_misses",
+                self.block_misses.load(Ordering::Relaxed),
+            ),
+            ("block_cache_bytes", lru.used as u64),
+            ("block_cache_entries", lru.entries.len() as u64),
+        ]
+    }
+}
+
+/// Least recently used map with a byte budget. Each use stamps an entry with
+/// a new tick; `order` maps ticks back to keys so the oldest is evicted first.
+struct Lru<K, V> {
+    capacity: usize,
+    used: usize,
+    tick: u64,
+    entries: HashMap<K, (V, usize, u64)>,
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Lru<K, V> {
+    fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let (value, _, tick) = self.entries.get_mut(key)?;
+        self.order.remove(tick);
+        *tick = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: K, value: V, size: usize) {
+        // Something bigger than the whole cache is just not cached
+        if size > self.capacity {
+            return;
+        }
+        self.remove(&key);
+
+        while self.used + size > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((_, size, _)) = self.entries.remove(&oldest) {
+                self.used -= size;
+            }
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, size, self.tick));
+        self.used += size;
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some((_, size, tick)) = self.entries.remove(key) {
+            self.order.remove(&tick);
+            self.used -= size;
+        }
+    }
+
+    fn retain(&mut self, keep: impl Fn(&K) -> bool) {
+        let stale: Vec<K> = self.entries.keys().filter(|k| !keep(k)).cloned().collect();
+        for key in stale {
+            self.remove(&key);
+        }
+    }
+}
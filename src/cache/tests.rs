@@ -0,0 +1,52 @@
+use crate::cache::BlockCache;
+
+fn stat(cache: &BlockCache, name: &str) -> u64 {
+    cache
+        .stats()
+        .into_iter()
+        .find(|(stat, _)| *stat == name)
+        .unwrap()
+        .1
+}
+
+/// Reads a 100-byte block through the cache, returning true on a hit.
+fn read(cache: &BlockCache, sstable_id: &str, offset: u64) -> bool {
+    let misses = stat(cache, "block_cache_misses");
+    let block = cache
+        .block(sstable_id, offset, || Ok(vec![offset as u8; 100]))
+        .unwrap();
+    assert_eq!(*block, vec![offset as u8; 100]);
+    stat(cache, "block_cache_misses") == misses
+}
+
+#[test]
+fn invalidate_drops_only_that_table() {
+    let cache = BlockCache::new(10_000);
+    for offset in 0..3 {
+        assert!(!read(&cache, "000001.db", offset));
+        assert!(!read(&cache, "000002.db", offset));
+    }
+    assert!(read(&cache, "000001.db", 0));
+    assert_eq!(stat(&cache, "block_cache_entries"), 6);
+
+    cache.invalidate("000001.db");
+    assert_eq!(stat(&cache, "block_cache_entries"), 3);
+    assert_eq!(stat(&cache, "block_cache_bytes"), 300);
+    assert!(!read(&cache, "000001.db", 0));
+    assert!(read(&cache, "000002.db", 0));
+}
+
+#[test]
+fn least_recently_used_blocks_are_evicted_first() {
+    let cache = BlockCache::new(300);
+    for offset in 0..3 {
+        read(&cache, "000001.db", offset);
+    }
+    // Block 0 is now the most recently used, so block 1 goes
+    assert!(read(&cache, "000001.db", 0));
+    assert!(!read(&cache, "000001.db", 3));
+    assert_eq!(stat(&cache, "block_cache_bytes"), 300);
+    assert!(read(&cache, "000001.db", 0));
+    assert!(read(&cache, "000001.db", 2));
+    assert!(!read(&cache, "000001.db", 1));
+}
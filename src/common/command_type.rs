@@ -3,6 +3,16 @@ pub enum CommandType {
     Get,
     GetKeys,
     Delete,
+    Stats,
+    Hello,
+    Expire,
+    Ttl,
+    Persist,
+    Incr,
+    Decr,
+    IncrBy,
+    IncrByFloat,
+    Cas,
 }
 
 impl CommandType {
@@ -12,6 +22,16 @@ impl CommandType {
             CommandType::Get => "GET",
             CommandType::GetKeys => "GET_KEYS",
             CommandType::Delete => "DELETE",
+            CommandType::Stats => "STATS",
+            CommandType::Hello => "HELLO",
+            CommandType::Expire => "EXPIRE",
+            CommandType::Ttl => "TTL",
+            CommandType::Persist => "PERSIST",
+            CommandType::Incr => "INCR",
+            CommandType::Decr => "DECR",
+            CommandType::IncrBy => "INCRBY",
+            CommandType::IncrByFloat => "INCRBYFLOAT",
+            CommandType::Cas => "CAS",
         }
     }
 
@@ -21,6 +41,16 @@ impl CommandType {
             "GET" => Some(CommandType::Get),
             "GET_KEYS" => Some(CommandType::GetKeys),
             "DELETE" => Some(CommandType::Delete),
+            "STATS" => Some(CommandType::Stats),
+            "HELLO" => Some(CommandType::Hello),
+            "EXPIRE" => Some(CommandType::Expire),
+            "TTL" => Some(CommandType::Ttl),
+            "PERSIST" => Some(CommandType::Persist),
+            "INCR" => Some(CommandType::Incr),
+            "DECR" => Some(CommandType::Decr),
+            "INCRBY" => Some(CommandType::IncrBy),
+            "INCRBYFLOAT" => Some(CommandType::IncrByFloat),
+            "CAS" => Some(CommandType::Cas),
             _ => None,
         }
     }
@@ -1,7 +1,20 @@
+use std::fmt;
+
 #[derive(Debug)]
 pub enum DbError {
     InvalidCommand(&'static str),
     KeyNotFound(String),
+    /// The key was deleted, as opposed to never having been written.
+    Deleted(String),
+    /// An integer increment hit a value that isn't a 64-bit integer.
+    NotAnInteger(String),
+    /// A float increment hit a value that isn't a number.
+    NotAFloat(String),
+    /// An increment would take the value out of range.
+    Overflow(String),
+    /// A conditional write (`SET ... NX`/`XX`, `CAS`) found the key in a
+    /// different state, so nothing was written.
+    ConditionFailed(String),
     SaveFailed(String),
     LoadFailed(String),
     WalStoreFailed(String),
@@ -9,4 +22,56 @@ pub enum DbError {
     SSTableWriteFailed(String),
     TombStoneFound,
     KeyNotInFile,
+    /// A checksum didn't match in an SSTable, at this byte offset.
+    Corruption {
+        file: String,
+        offset: u64,
+    },
+}
+
+impl DbError {
+    /// Stable error code sent to clients. Codes are part of the protocol, so
+    /// existing ones must not change.
+    pub fn code(&self) -> &'static str {
+        match self {
+            DbError::InvalidCommand(_) => "INVALID_COMMAND",
+            DbError::KeyNotFound(_) => "NOT_FOUND",
+            DbError::Deleted(_) => "DELETED",
+            DbError::NotAnInteger(_) => "NOT_AN_INTEGER",
+            DbError::NotAFloat(_) => "NOT_A_FLOAT",
+            DbError::Overflow(_) => "OVERFLOW",
+            DbError::ConditionFailed(_) => "CONDITION_FAILED",
+            DbError::SaveFailed(_) => "SAVE_FAILED",
+            DbError::LoadFailed(_) => "LOAD_FAILED",
+            DbError::WalStoreFailed(_) => "WAL_FAILED",
+            DbError::SSTableReadFailed(_) => "READ_FAILED",
+            DbError::SSTableWriteFailed(_) => "WRITE_FAILED",
+            DbError::TombStoneFound | DbError::KeyNotInFile => "INTERNAL",
+            DbError::Corruption { .. } => "CORRUPTION",
+        }
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::InvalidCommand(message) => write!(f, "{}", message),
+            DbError::KeyNotFound(message) => write!(f, "{}", message),
+            DbError::Deleted(key) => write!(f, "Key {} was deleted", key),
+            DbError::NotAnInteger(key) => write!(f, "Value of {} is not an integer", key),
+            DbError::NotAFloat(key) => write!(f, "Value of {} is not a number", key),
+            DbError::Overflow(key) => write!(f, "Increment of {} would overflow", key),
+            DbError::ConditionFailed(key) => write!(f, "Condition on {} was not met", key),
+            DbError::SaveFailed(e) => write!(f, "Save failed: {}", e),
+            DbError::LoadFailed(e) => write!(f, "Load failed: {}", e),
+            DbError::WalStoreFailed(e) => write!(f, "WAL write failed: {}", e),
+            DbError::SSTableReadFailed(e) => write!(f, "SSTable read failed: {}", e),
+            DbError::SSTableWriteFailed(e) => write!(f, "SSTable write failed: {}", e),
+            DbError::TombStoneFound => write!(f, "Key was deleted"),
+            DbError::KeyNotInFile => write!(f, "Key not in file"),
+            DbError::Corruption { file, offset } => {
+                write!(f, "Checksum mismatch in {} at offset {}", file, offset)
+            }
+        }
+    }
 }
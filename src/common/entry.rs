@@ -0,0 +1,61 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The newest write to a key, tagged with the WAL sequence number it was
+/// logged under. Deletes are their own variant, so no value can be mistaken
+/// for one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    Put {
+        seq: u64,
+        value: Vec<u8>,
+        /// When the value expires, in Unix milliseconds. `None` never expires.
+        expires_at: Option<u64>,
+    },
+    Delete {
+        seq: u64,
+    },
+}
+
+impl Entry {
+    pub fn seq(&self) -> u64 {
+        match self {
+            Entry::Put { seq, .. } | Entry::Delete { seq } => *seq,
+        }
+    }
+
+    /// The value written, or `None` for a delete.
+    pub fn value(&self) -> Option<&[u8]> {
+        match self {
+            Entry::Put { value, .. } => Some(value),
+            Entry::Delete { .. } => None,
+        }
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        match self {
+            Entry::Put { expires_at, .. } => *expires_at,
+            Entry::Delete { .. } => None,
+        }
+    }
+
+    pub fn is_delete(&self) -> bool {
+        matches!(self, Entry::Delete { .. })
+    }
+
+    /// A value past its expiry time. Reads treat it like a delete.
+    pub fn is_expired(&self, now_millis: u64) -> bool {
+        is_expired(self.expires_at(), now_millis)
+    }
+}
+
+/// Whether a value with expiry time `expires_at` has expired at `now_millis`.
+pub fn is_expired(expires_at: Option<u64>, now_millis: u64) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= now_millis)
+}
+
+/// Current time in Unix milliseconds, the unit of expiry times.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
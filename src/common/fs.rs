@@ -0,0 +1,10 @@
+use std::fs::File;
+
+use crate::common::db_errors::DbError;
+
+/// fsync a directory, so renames and removals in it survive a crash.
+pub fn sync_dir(dir: &str) -> Result<(), DbError> {
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| DbError::SaveFailed(format!("Failed to sync directory {}: {}", dir, e)))
+}
@@ -1,2 +1,4 @@
 pub mod command_type;
 pub mod db_errors;
+pub mod entry;
+pub mod fs;
@@ -0,0 +1,174 @@
+use std::fs;
+
+use serde::Deserialize;
+
+use crate::{
+    bloom::DEFAULT_BITS_PER_KEY, cache::DEFAULT_BLOCK_CACHE_BYTES, common::db_errors::DbError,
+    memtable::DEFAULT_MEMTABLE_SIZE_LIMIT_BYTES,
+    storage_engine::sstable_engine::DEFAULT_TARGET_FILE_SIZE_BYTES,
+    wal::DEFAULT_SEGMENT_SIZE_BYTES,
+};
+
+/// Default config file, read from the working directory.
+pub const DEFAULT_CONFIG_PATH: &str = "mdb.toml";
+
+/// Server configuration, loaded from a TOML file. Every field has a default,
+/// so a missing file or section just means "use the defaults".
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub wal: WalConfig,
+    pub memtable: MemtableConfig,
+    pub sstable: SSTableConfig,
+    pub compaction: CompactionConfig,
+    pub http: HttpConfig,
+    pub expiry: ExpiryConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct WalConfig {
+    /// When WAL appends are fsynced, like Redis's `appendfsync`.
+    pub fsync: FsyncPolicy,
+    /// Size at which the active segment is sealed and a new one is started.
+    pub segment_size_bytes: u64,
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        WalConfig {
+            fsync: FsyncPolicy::Always,
+            segment_size_bytes: DEFAULT_SEGMENT_SIZE_BYTES,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct MemtableConfig {
+    /// Size at which the memtable is frozen and flushed to an SSTable.
+    pub size_limit_bytes: usize,
+}
+
+impl Default for MemtableConfig {
+    fn default() -> Self {
+        MemtableConfig {
+            size_limit_bytes: DEFAULT_MEMTABLE_SIZE_LIMIT_BYTES,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SSTableConfig {
+    /// Bloom filter bits per key in new SSTables. 0 disables bloom filters.
+    pub bloom_bits_per_key: usize,
+    /// Size of the LRU cache of SSTable indexes and data blocks.
+    pub block_cache_bytes: usize,
+    /// Compaction splits its output into files of about this size.
+    pub target_file_size_bytes: u64,
+}
+
+impl Default for SSTableConfig {
+    fn default() -> Self {
+        SSTableConfig {
+            bloom_bits_per_key: DEFAULT_BITS_PER_KEY,
+            block_cache_bytes: DEFAULT_BLOCK_CACHE_BYTES,
+            target_file_size_bytes: DEFAULT_TARGET_FILE_SIZE_BYTES,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct CompactionConfig {
+    pub strategy: CompactionStrategyKind,
+    /// Leveled: number of L0 files that triggers a compaction into L1.
+    pub level0_file_trigger: usize,
+    /// Size limit of L1.
+    pub level_base_bytes: u64,
+    /// Each level below L1 may hold this many times more than the one above.
+    pub level_size_ratio: u64,
+    /// Number of levels, including L0.
+    pub max_levels: u32,
+    /// Size-tiered: fewest similarly sized runs that are merged together.
+    pub min_threshold: usize,
+    /// Most runs merged in one compaction.
+    pub max_threshold: usize,
+    /// A run is similar in size if it is within this factor of the bucket average.
+    pub bucket_ratio: f64,
+    /// Tombstones are kept at least this long, even once no older data is
+    /// left for them to hide.
+    pub tombstone_grace_secs: u64,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        CompactionConfig {
+            strategy: CompactionStrategyKind::Leveled,
+            level0_file_trigger: 4,
+            level_base_bytes: 10 * 1024 * 1024,
+            level_size_ratio: 10,
+            max_levels: 7,
+            min_threshold: 4,
+            max_threshold: 32,
+            bucket_ratio: 1.5,
+            tombstone_grace_secs: 0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Address of the HTTP/JSON listener, e.g. `127.0.0.1:8080`. Unset
+    /// leaves it off.
+    pub addr: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ExpiryConfig {
+    /// How often expired keys are deleted from the memtable.
+    pub sweep_interval_secs: u64,
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        ExpiryConfig {
+            sweep_interval_secs: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompactionStrategyKind {
+    /// LevelDB-style levels with growing size limits.
+    Leveled,
+    /// Merge groups of similarly sized SSTables, for write-heavy workloads.
+    SizeTiered,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// fsync before a write is acknowledged. Concurrent writers share one fsync.
+    Always,
+    /// A background task fsyncs once per second.
+    Everysec,
+    /// Never fsync explicitly and leave it to the OS.
+    None,
+}
+
+impl Config {
+    /// Reads the config at `path`, falling back to defaults if it doesn't exist.
+    pub fn load(path: &str) -> Result<Self, DbError> {
+        match fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content)
+                .map_err(|e| DbError::LoadFailed(format!("invalid config {}: {}", path, e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(DbError::LoadFailed(e.to_string())),
+        }
+    }
+}
@@ -1,81 +1,683 @@
-use std::collections::BTreeMap;
+#[cfg(test)]
+pub(crate) mod tests;
+
+use std::{
+    collections::BTreeMap,
+    mem,
+    sync::{Arc, Mutex, RwLock},
+};
 
 use crate::{
-    common::{command_type::CommandType, db_errors::DbError},
+    common::{
+        db_errors::DbError,
+        entry::{Entry, unix_millis},
+    },
+    config::MemtableConfig,
+    ende::table::StoredValue,
+    memtable::{ImmutableMemtables, MAX_IMMUTABLE_MEMTABLES, Memtable},
     storage_engine::engine::Engine,
     wal::Wal,
 };
 
+/// The memtables in front of the storage engine. Every method takes `&self`,
+/// so one `Db` is shared by all connections.
+///
+/// Reads take the active memtable's read lock only long enough to look the
+/// key up, and read frozen memtables and SSTables without any `Db` lock.
+/// Writers queue on `write_lock` for the WAL append and memtable insert, and
+/// wait for their fsync after releasing it.
 pub struct Db<E: Engine> {
-    pub data: BTreeMap<String, String>,
-    pub engine: E,
-    pub wal: Wal<E>,
+    pub data: RwLock<Memtable>,
+    /// Held from a write's WAL append until it is in the memtable, so the
+    /// memtable sees writes in sequence order and a freeze never lands between
+    /// the two.
+    write_lock: Mutex<()>,
+    /// Full memtables still being flushed. Reads check them after `data`.
+    pub immutables: Arc<ImmutableMemtables>,
+    pub engine: Arc<E>,
+    pub wal: Arc<Wal>,
+    pub memtable_size_limit: usize,
 }
 
 impl<E: Engine> Db<E> {
-    pub fn new(engine: E, wal: Wal<E>) -> Result<Self, DbError> {
-        let data = engine.load()?;
-        Ok(Db { data, engine, wal })
+    pub fn new(
+        engine: Arc<E>,
+        wal: Arc<Wal>,
+        immutables: Arc<ImmutableMemtables>,
+        config: &MemtableConfig,
+    ) -> Result<Self, DbError> {
+        let mut data = Memtable::new();
+        let recovered = wal.replay_into(&mut data)?;
+        println!("Recovered {} records from WAL", recovered);
+
+        let db = Db {
+            data: RwLock::new(data),
+            write_lock: Mutex::new(()),
+            immutables,
+            engine,
+            wal,
+            memtable_size_limit: config.size_limit_bytes,
+        };
+        db.flush_to_persist()?;
+
+        Ok(db)
     }
 
-    pub fn handle_set(&mut self, splitted_instruction: &[&str]) -> Result<(), DbError> {
+    /// `SET key value [EX seconds | PX milliseconds] [NX | XX]`. With `NX`
+    /// the key is only set if it doesn't exist, with `XX` only if it does;
+    /// otherwise the result is `DbError::ConditionFailed`. Returns the WAL
+    /// sequence number of the write, for `Wal::sync_to`.
+    pub fn handle_set(&self, splitted_instruction: &[Vec<u8>]) -> Result<u64, DbError> {
         if splitted_instruction.len() < 3 {
             return Err(DbError::InvalidCommand(
                 "Invalid SET instruction. It needs a key and value",
             ));
         }
+        let (expires_at, condition) = parse_set_options(&splitted_instruction[3..])?;
 
-        let k = splitted_instruction[1].to_string();
-        let v = splitted_instruction[2..].join(" ");
+        let k = splitted_instruction[1].clone();
+        let v = splitted_instruction[2].clone();
 
-        self.wal.store_wal(CommandType::Set.as_str(), &k, &v)?;
+        let Some(condition) = condition else {
+            return self.write(k, Some(v), expires_at);
+        };
+        // Held across the check so no other write can change the key before
+        // this one lands
+        let _writer = self.write_lock.lock().unwrap();
+        let exists = self.existing(&k)?.is_some();
+        if exists != (condition == SetCondition::IfPresent) {
+            return Err(DbError::ConditionFailed(
+                String::from_utf8_lossy(&k).into_owned(),
+            ));
+        }
+        self.write_locked(k, Some(v), expires_at)
+    }
 
-        self.data.insert(k, v);
+    /// `CAS key expected new`: sets the key to `new` only if its current
+    /// value is `expected`, and otherwise fails with
+    /// `DbError::ConditionFailed`. Like `SET`, the new value doesn't expire.
+    pub fn handle_cas(&self, splitted_instruction: &[Vec<u8>]) -> Result<u64, DbError> {
+        if splitted_instruction.len() != 4 {
+            return Err(DbError::InvalidCommand(
+                "Invalid CAS instruction. It needs a key, the expected value and the new value",
+            ));
+        }
 
-        Ok(())
+        let key = &splitted_instruction[1];
+        let _writer = self.write_lock.lock().unwrap();
+        match self.existing(key)? {
+            Some(stored) if stored.data == splitted_instruction[2] => {
+                self.write_locked(key.clone(), Some(splitted_instruction[3].clone()), None)
+            }
+            _ => Err(DbError::ConditionFailed(
+                String::from_utf8_lossy(key).into_owned(),
+            )),
+        }
     }
 
-    pub fn handle_get(&self, splitted_instructions: &[&str]) -> Result<String, DbError> {
+    pub fn handle_get(&self, splitted_instructions: &[Vec<u8>]) -> Result<Vec<u8>, DbError> {
         if splitted_instructions.len() < 2 {
             return Err(DbError::InvalidCommand(
                 "Invalid GET instruction. It needs the key",
             ));
         }
 
-        let key = splitted_instructions[1];
+        Ok(self.lookup(&splitted_instructions[1])?.data)
+    }
+
+    /// `EXPIRE key seconds`: sets the key to expire `seconds` from now, or
+    /// deletes it if `seconds` isn't positive. Returns the WAL sequence number
+    /// of the write, or `None` if there is no such key.
+    pub fn handle_expire(&self, splitted_instruction: &[Vec<u8>]) -> Result<Option<u64>, DbError> {
+        if splitted_instruction.len() != 3 {
+            return Err(DbError::InvalidCommand(
+                "Invalid EXPIRE instruction. It needs a key and seconds",
+            ));
+        }
+        let seconds: i64 = std::str::from_utf8(&splitted_instruction[2])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or(DbError::InvalidCommand(
+                "EXPIRE takes a whole number of seconds",
+            ))?;
+
+        let key = &splitted_instruction[1];
+        // Held across the read so no write lands between it and the rewrite
+        let _writer = self.write_lock.lock().unwrap();
+        let Some(StoredValue { data: value, .. }) = self.existing(key)? else {
+            return Ok(None);
+        };
+
+        let seq = if seconds <= 0 {
+            self.write_locked(key.clone(), None, None)?
+        } else {
+            let expires_at = unix_millis().saturating_add((seconds as u64).saturating_mul(1000));
+            self.write_locked(key.clone(), Some(value), Some(expires_at))?
+        };
+        Ok(Some(seq))
+    }
+
+    /// `TTL key`: seconds until the key expires, -1 if it never does and -2
+    /// if there is no such key.
+    pub fn handle_ttl(&self, splitted_instruction: &[Vec<u8>]) -> Result<i64, DbError> {
+        if splitted_instruction.len() != 2 {
+            return Err(DbError::InvalidCommand(
+                "Invalid TTL instruction. It needs the key",
+            ));
+        }
+
+        Ok(match self.existing(&splitted_instruction[1])? {
+            None => -2,
+            Some(StoredValue {
+                expires_at: None, ..
+            }) => -1,
+            Some(StoredValue {
+                expires_at: Some(expires_at),
+                ..
+            }) => {
+                let remaining = expires_at.saturating_sub(unix_millis());
+                remaining.div_ceil(1000) as i64
+            }
+        })
+    }
+
+    /// `PERSIST key`: removes the key's expiry time. Returns the WAL sequence
+    /// number of the write, or `None` if there is no such key or it has no
+    /// expiry time.
+    pub fn handle_persist(&self, splitted_instruction: &[Vec<u8>]) -> Result<Option<u64>, DbError> {
+        if splitted_instruction.len() != 2 {
+            return Err(DbError::InvalidCommand(
+                "Invalid PERSIST instruction. It needs the key",
+            ));
+        }
+
+        let key = &splitted_instruction[1];
+        let _writer = self.write_lock.lock().unwrap();
+        match self.existing(key)? {
+            Some(StoredValue {
+                data: value,
+                expires_at: Some(_),
+            }) => Ok(Some(self.write_locked(key.clone(), Some(value), None)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// `INCR key`: adds 1 to the integer at `key`. Returns the new value and
+    /// the WAL sequence number of the write.
+    pub fn handle_incr(&self, splitted_instruction: &[Vec<u8>]) -> Result<(i64, u64), DbError> {
+        if splitted_instruction.len() != 2 {
+            return Err(DbError::InvalidCommand(
+                "Invalid INCR instruction. It needs the key",
+            ));
+        }
+        self.incr_by(&splitted_instruction[1], 1)
+    }
+
+    /// `DECR key`: subtracts 1 from the integer at `key`.
+    pub fn handle_decr(&self, splitted_instruction: &[Vec<u8>]) -> Result<(i64, u64), DbError> {
+        if splitted_instruction.len() != 2 {
+            return Err(DbError::InvalidCommand(
+                "Invalid DECR instruction. It needs the key",
+            ));
+        }
+        self.incr_by(&splitted_instruction[1], -1)
+    }
+
+    /// `INCRBY key delta`: adds `delta`, which may be negative, to the
+    /// integer at `key`.
+    pub fn handle_incr_by(&self, splitted_instruction: &[Vec<u8>]) -> Result<(i64, u64), DbError> {
+        if splitted_instruction.len() != 3 {
+            return Err(DbError::InvalidCommand(
+                "Invalid INCRBY instruction. It needs a key and an increment",
+            ));
+        }
+        let delta = parse_number(&splitted_instruction[2])
+            .ok_or(DbError::InvalidCommand("INCRBY takes a whole number"))?;
+        self.incr_by(&splitted_instruction[1], delta)
+    }
+
+    /// `INCRBYFLOAT key delta`: adds the float `delta` to the number at
+    /// `key`. Returns the new value as it is stored, and the WAL sequence
+    /// number of the write.
+    pub fn handle_incr_by_float(
+        &self,
+        splitted_instruction: &[Vec<u8>],
+    ) -> Result<(Vec<u8>, u64), DbError> {
+        if splitted_instruction.len() != 3 {
+            return Err(DbError::InvalidCommand(
+                "Invalid INCRBYFLOAT instruction. It needs a key and an increment",
+            ));
+        }
+        let delta = parse_number::<f64>(&splitted_instruction[2])
+            .filter(|delta| delta.is_finite())
+            .ok_or(DbError::InvalidCommand("INCRBYFLOAT takes a number"))?;
+
+        let key = &splitted_instruction[1];
+        let _writer = self.write_lock.lock().unwrap();
+        let (current, expires_at) = match self.existing(key)? {
+            Some(stored) => match parse_number::<f64>(&stored.data) {
+                Some(current) if current.is_finite() => (current, stored.expires_at),
+                _ => {
+                    return Err(DbError::NotAFloat(
+                        String::from_utf8_lossy(key).into_owned(),
+                    ));
+                }
+            },
+            None => (0.0, None),
+        };
+
+        let value = current + delta;
+        if !value.is_finite() {
+            return Err(DbError::Overflow(String::from_utf8_lossy(key).into_owned()));
+        }
+        let value = value.to_string().into_bytes();
+        let seq = self.write_locked(key.clone(), Some(value.clone()), expires_at)?;
+        Ok((value, seq))
+    }
+
+    /// Adds `delta` to the integer at `key`, read like `handle_get` would,
+    /// and writes back the result. A missing, deleted or expired key counts
+    /// as 0. The key keeps its expiry time.
+    fn incr_by(&self, key: &[u8], delta: i64) -> Result<(i64, u64), DbError> {
+        // Held across the read so concurrent increments can't both read the
+        // same value
+        let _writer = self.write_lock.lock().unwrap();
+        let (current, expires_at) = match self.existing(key)? {
+            Some(stored) => match parse_number::<i64>(&stored.data) {
+                Some(current) => (current, stored.expires_at),
+                None => {
+                    return Err(DbError::NotAnInteger(
+                        String::from_utf8_lossy(key).into_owned(),
+                    ));
+                }
+            },
+            None => (0, None),
+        };
+
+        let value = current
+            .checked_add(delta)
+            .ok_or_else(|| DbError::Overflow(String::from_utf8_lossy(key).into_owned()))?;
+        let seq = self.write_locked(
+            key.to_vec(),
+            Some(value.to_string().into_bytes()),
+            expires_at,
+        )?;
+        Ok((value, seq))
+    }
 
-        let potential_res = self.data.get(key);
+    /// The newest value of `key` and when it expires. An expired value is
+    /// reported as `Deleted`, without looking for older versions.
+    fn lookup(&self, key: &[u8]) -> Result<StoredValue, DbError> {
+        // Frozen tables are only looked at once the active one has been read,
+        // so a table frozen in between is still found there
+        let active = self.data.read().unwrap().get(key).cloned();
+        let potential_res = match active {
+            Some(entry) => Some(entry),
+            None => self
+                .immutables
+                .newest_first()
+                .iter()
+                .find_map(|m| m.get(key).cloned()),
+        };
 
-        match potential_res {
-            Some(x) => Ok(x.to_string()),
-            None => match self.engine.get_value(key.to_string()) {
-                Ok(val) => Ok(val),
-                Err(e) => Err(e),
+        let stored = match potential_res {
+            Some(Entry::Put {
+                value, expires_at, ..
+            }) => StoredValue {
+                data: value,
+                expires_at,
             },
+            Some(Entry::Delete { .. }) => {
+                return Err(DbError::Deleted(String::from_utf8_lossy(key).into_owned()));
+            }
+            None => self.engine.get_value(key)?,
+        };
+
+        // The sweeper replaces expired values with deletes, so they read the
+        // same before and after it gets to them
+        if stored.is_expired(unix_millis()) {
+            return Err(DbError::Deleted(String::from_utf8_lossy(key).into_owned()));
+        }
+        Ok(stored)
+    }
+
+    /// `lookup`, with missing, deleted and expired keys as `None`.
+    fn existing(&self, key: &[u8]) -> Result<Option<StoredValue>, DbError> {
+        match self.lookup(key) {
+            Ok(found) => Ok(Some(found)),
+            Err(DbError::KeyNotFound(_) | DbError::Deleted(_)) => Ok(None),
+            Err(e) => Err(e),
         }
     }
 
-    pub fn handle_delete(&mut self, splitted_instruction: &[&str]) -> Result<(), DbError> {
+    /// Returns the WAL sequence number of the write, for `Wal::sync_to`.
+    pub fn handle_delete(&self, splitted_instruction: &[Vec<u8>]) -> Result<u64, DbError> {
         if splitted_instruction.len() < 2 {
             return Err(DbError::InvalidCommand(
                 "Number of argument too low for delete. Need to know the key",
             ));
         }
 
-        let key = splitted_instruction[1];
-        self.wal.store_wal(
-            CommandType::Delete.as_str(),
-            &key.to_string(),
-            &String::new(),
-        )?;
+        let key = &splitted_instruction[1];
+        // Keep the delete so older values in the SSTables stay hidden
+        let seq = self.write(key.clone(), None, None)?;
+
+        println!("Deleted key {}", String::from_utf8_lossy(key));
+        Ok(seq)
+    }
+
+    /// `DEL key...`: deletes the keys that exist and returns how many there
+    /// were, with the WAL sequence number of the last delete, or `None` if
+    /// none existed. Missing keys get no delete, and the keys are checked and
+    /// deleted under one hold of `write_lock`, so the count is exact.
+    pub fn delete_existing(&self, keys: &[Vec<u8>]) -> Result<(usize, Option<u64>), DbError> {
+        let _writer = self.write_lock.lock().unwrap();
+        let mut existing = Vec::new();
+        for key in keys {
+            if self.existing(key)?.is_some() && !existing.contains(&key) {
+                existing.push(key);
+            }
+        }
+
+        let mut last_seq = None;
+        for key in &existing {
+            last_seq = Some(self.write_locked(key.to_vec(), None, None)?);
+        }
+        Ok((existing.len(), last_seq))
+    }
+
+    /// Logs the write to the WAL and applies it to the memtable. Returns its
+    /// sequence number, for `Wal::sync_to`.
+    fn write(
+        &self,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        expires_at: Option<u64>,
+    ) -> Result<u64, DbError> {
+        let _writer = self.write_lock.lock().unwrap();
+        self.write_locked(key, value, expires_at)
+    }
+
+    /// `write` for callers already holding `write_lock`.
+    fn write_locked(
+        &self,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        expires_at: Option<u64>,
+    ) -> Result<u64, DbError> {
+        let entry = self.wal.store_wal(&key, value, expires_at)?;
+        let seq = entry.seq();
+        self.data.write().unwrap().insert(key, entry);
+
+        if let Err(e) = self.freeze_if_full() {
+            // The write is already in the WAL and memtable, so a failed flush
+            // is only logged; it is flushed again from the WAL after a restart
+            println!("Memtable flush failed: {:?}", e);
+        }
+
+        Ok(seq)
+    }
+
+    /// Deletes the keys in the active memtable whose values have expired and
+    /// returns how many there were. Expired values that were already frozen
+    /// are hidden from reads and dropped when compaction reaches them.
+    pub fn sweep_expired(&self) -> Result<usize, DbError> {
+        let now = unix_millis();
+        let expired: Vec<Vec<u8>> = self
+            .data
+            .read()
+            .unwrap()
+            .data
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        let _writer = self.write_lock.lock().unwrap();
+        let mut swept = 0;
+        for key in expired {
+            // Skip keys written again since they were collected
+            let still_expired = self
+                .data
+                .read()
+                .unwrap()
+                .get(&key)
+                .is_some_and(|entry| entry.is_expired(now));
+            if still_expired {
+                self.write_locked(key, None, None)?;
+                swept += 1;
+            }
+        }
+        Ok(swept)
+    }
+
+    /// The first `limit` live keys starting with `prefix`, in order, from
+    /// the SSTables and memtables, skipping deleted and expired ones.
+    pub fn keys(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>, DbError> {
+        // Deletes in the memtables can hide scanned keys, so a scan of `limit`
+        // keys may come up short. Scan further until it doesn't.
+        let mut scan_limit = limit;
+        loop {
+            if let Some(keys) = self.scan_keys(prefix, limit, scan_limit)? {
+                return Ok(keys);
+            }
+            scan_limit = scan_limit.saturating_mul(2);
+        }
+    }
+
+    /// `keys` with the SSTables scanned for only `scan_limit` keys, or `None`
+    /// if that left too few to be sure of the first `limit`.
+    fn scan_keys(
+        &self,
+        prefix: &[u8],
+        limit: usize,
+        scan_limit: usize,
+    ) -> Result<Option<Vec<Vec<u8>>>, DbError> {
+        let now = unix_millis();
+        // Taken before the scan so a table flushed during it is still seen
+        let frozen_before_scan = self.immutables.newest_first();
 
-        self.data.remove(key);
+        // Keys past the last one scanned may be missing from the SSTables
+        let scanned = self.engine.scan(prefix, scan_limit)?;
+        let scanned_up_to = match scanned.last() {
+            Some((key, _)) if scanned.len() >= scan_limit => Some(key.clone()),
+            _ => None,
+        };
+
+        // Whether each key is live, oldest source first so newer writes win
+        let mut merged: BTreeMap<Vec<u8>, bool> = scanned
+            .into_iter()
+            .map(|(key, value)| (key, value.is_some_and(|v| !v.is_expired(now))))
+            .collect();
+
+        let mut merge = |memtable: &Memtable| {
+            let matching = memtable
+                .data
+                .range(prefix.to_vec()..)
+                .take_while(|(key, _)| key.starts_with(prefix));
+            for (key, entry) in matching {
+                merged.insert(key.clone(), !entry.is_delete() && !entry.is_expired(now));
+            }
+        };
+        // Tables frozen during the scan are only in the second snapshot. Both
+        // are oldest first and the second never holds older tables than the
+        // first, so merging one after the other keeps newer writes on top.
+        let active = self.data.read().unwrap();
+        let frozen_now = self.immutables.newest_first();
+        for memtable in frozen_before_scan
+            .iter()
+            .rev()
+            .chain(frozen_now.iter().rev())
+        {
+            merge(memtable);
+        }
+        merge(&active);
+        drop(active);
+
+        let keys: Vec<Vec<u8>> = merged
+            .into_iter()
+            .filter(|(_, live)| *live)
+            .map(|(key, _)| key)
+            .take(limit)
+            .collect();
+        let complete = match &scanned_up_to {
+            Some(last) => keys.len() == limit && keys.last().is_none_or(|key| key <= last),
+            None => true,
+        };
+        Ok(complete.then_some(keys))
+    }
+
+    /// Memtable and storage engine counters, as `(name, value)` pairs.
+    pub fn stats(&self) -> Vec<(&'static str, u64)> {
+        let mut stats = vec![
+            (
+                "memtable_bytes",
+                self.data.read().unwrap().size_bytes as u64,
+            ),
+            ("immutable_memtables", self.immutables.len() as u64),
+        ];
+        stats.extend(self.engine.stats());
+        stats
+    }
+
+    /// Runs `f` against the db on tokio's blocking pool. Reads and writes hit
+    /// the disk (WAL appends, SSTable lookups, flushes once the flusher falls
+    /// behind), so the servers call the db through here and never stall the
+    /// workers handling network I/O.
+    pub async fn run_blocking<T, F>(self: &Arc<Self>, f: F) -> T
+    where
+        E: Send + Sync + 'static,
+        T: Send + 'static,
+        F: FnOnce(&Db<E>) -> T + Send + 'static,
+    {
+        let db = self.clone();
+        match tokio::task::spawn_blocking(move || f(&db)).await {
+            Ok(result) => result,
+            // Surface a panic in `f` here, as if it had run on this task
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+
+    /// Runs a command with `run_blocking`, then waits until the write it
+    /// made is durable before handing back its result. `f` returns the WAL
+    /// sequence number of its write, or `None` if it didn't write. The fsync
+    /// runs after the write lock is released, so concurrent writers share it.
+    pub async fn run_write<T, F>(self: &Arc<Self>, f: F) -> Result<T, DbError>
+    where
+        E: Send + Sync + 'static,
+        T: Send + 'static,
+        F: FnOnce(&Db<E>) -> (T, Option<u64>) + Send + 'static,
+    {
+        let (result, seq) = self.run_blocking(f).await;
+        if let Some(seq) = seq {
+            self.wal.sync_to(seq).await?;
+        }
+        Ok(result)
+    }
+
+    /// Freezes the memtable if it has grown past `memtable_size_limit`.
+    pub fn flush_to_persist(&self) -> Result<(), DbError> {
+        let _writer = self.write_lock.lock().unwrap();
+        self.freeze_if_full()
+    }
+
+    /// Freezes the memtable once it grows past `memtable_size_limit` and hands
+    /// it to the flusher, which writes it to an SSTable and truncates the WAL.
+    /// If the flusher has fallen behind, the oldest frozen table is flushed
+    /// here first so memory stays bounded. That waits for at most one other
+    /// flush, never for a compaction. Callers hold `write_lock`.
+    fn freeze_if_full(&self) -> Result<(), DbError> {
+        if self.data.read().unwrap().size_bytes < self.memtable_size_limit {
+            return Ok(());
+        }
+
+        while self.immutables.len() >= MAX_IMMUTABLE_MEMTABLES {
+            self.immutables
+                .flush_oldest(self.engine.as_ref(), &self.wal)?;
+        }
+
+        // Start a new segment so the frozen table's records can be dropped
+        // as whole segments once it is flushed
+        self.wal.roll_segment()?;
+
+        // Queued before the write lock is released, so readers that miss in
+        // the new active table find the frozen one
+        let mut active = self.data.write().unwrap();
+        let frozen = mem::take(&mut *active);
+        println!(
+            "Freezing memtable ({} bytes, up to seq {})",
+            frozen.size_bytes, frozen.last_seq
+        );
+        self.immutables.push(frozen);
 
-        println!("Deleted key {}", splitted_instruction[1]);
         Ok(())
     }
+}
+
+/// `SET ... NX` and `SET ... XX`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SetCondition {
+    IfAbsent,
+    IfPresent,
+}
+
+/// The expiry time and condition from the options after `SET key value`.
+fn parse_set_options(options: &[Vec<u8>]) -> Result<(Option<u64>, Option<SetCondition>), DbError> {
+    let mut expires_at = None;
+    let mut condition = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let this_condition = if option.eq_ignore_ascii_case(b"NX") {
+            SetCondition::IfAbsent
+        } else if option.eq_ignore_ascii_case(b"XX") {
+            SetCondition::IfPresent
+        } else {
+            let amount = options.next().ok_or(DbError::InvalidCommand(
+                "SET options are EX seconds, PX milliseconds, NX and XX",
+            ))?;
+            if expires_at.is_some() {
+                return Err(DbError::InvalidCommand("SET takes only one of EX and PX"));
+            }
+            expires_at = Some(parse_expiry(option, amount)?);
+            continue;
+        };
+        if condition.is_some() {
+            return Err(DbError::InvalidCommand("SET takes only one of NX and XX"));
+        }
+        condition = Some(this_condition);
+    }
+    Ok((expires_at, condition))
+}
+
+/// Expiry time for `SET ... EX seconds` or `SET ... PX milliseconds`.
+fn parse_expiry(unit: &[u8], amount: &[u8]) -> Result<u64, DbError> {
+    let millis_per_unit = if unit.eq_ignore_ascii_case(b"EX") {
+        1000
+    } else if unit.eq_ignore_ascii_case(b"PX") {
+        1
+    } else {
+        return Err(DbError::InvalidCommand(
+            "SET options are EX seconds, PX milliseconds, NX and XX",
+        ));
+    };
+    let amount: u64 =
+        parse_number(amount)
+            .filter(|amount| *amount > 0)
+            .ok_or(DbError::InvalidCommand(
+                "EX and PX take a positive whole number",
+            ))?;
+    Ok(unix_millis().saturating_add(amount.saturating_mul(millis_per_unit)))
+}
 
-    pub fn flush_to_persist(&self) {
-        println!("flushhhhhhhh")
+/// Parses an argument or stored value as a number, with no surrounding
+/// whitespace. A leading `+` is rejected, as in Redis, so `+5` stays a
+/// string.
+fn parse_number<T: std::str::FromStr>(bytes: &[u8]) -> Option<T> {
+    if bytes.first() == Some(&b'+') {
+        return None;
     }
+    std::str::from_utf8(bytes).ok()?.parse().ok()
 }
@@ -0,0 +1,493 @@
+use std::{
+    mem,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    common::{db_errors::DbError, entry::Entry},
+    config::{
+        CompactionConfig, CompactionStrategyKind, FsyncPolicy, MemtableConfig, SSTableConfig,
+        WalConfig,
+    },
+    db::Db,
+    memtable::ImmutableMemtables,
+    storage_engine::{engine::Engine, sstable_engine::SSTableEngine},
+    wal::Wal,
+};
+
+/// Fresh directory under the system temp dir, for the WAL and the SSTables.
+pub(crate) fn temp_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("mdb-db-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir.to_string_lossy().into_owned()
+}
+
+/// Opens the db in `dir`, replaying whatever an earlier open left there.
+pub(crate) fn open(dir: &str) -> Db<SSTableEngine> {
+    open_with(
+        dir,
+        &SSTableConfig::default(),
+        &CompactionConfig::default(),
+        &MemtableConfig::default(),
+    )
+}
+
+fn open_with(
+    dir: &str,
+    sstable: &SSTableConfig,
+    compaction: &CompactionConfig,
+    memtable: &MemtableConfig,
+) -> Db<SSTableEngine> {
+    let engine = SSTableEngine::with_config(format!("{}/data", dir), sstable, compaction).unwrap();
+    let wal = Wal::new(
+        format!("{}/wal", dir),
+        &WalConfig {
+            fsync: FsyncPolicy::None,
+            ..WalConfig::default()
+        },
+    )
+    .unwrap();
+    Db::new(
+        Arc::new(engine),
+        Arc::new(wal),
+        Arc::new(ImmutableMemtables::new()),
+        memtable,
+    )
+    .unwrap()
+}
+
+/// Freezes the active memtable and writes it to an SSTable, as the flusher
+/// would once it fills up.
+fn flush(db: &Db<SSTableEngine>) {
+    {
+        let _writer = db.write_lock.lock().unwrap();
+        db.wal.roll_segment().unwrap();
+        let frozen = mem::take(&mut *db.data.write().unwrap());
+        db.immutables.push(frozen);
+    }
+    while db
+        .immutables
+        .flush_oldest(db.engine.as_ref(), &db.wal)
+        .unwrap()
+    {}
+}
+
+fn args(args: &[&str]) -> Vec<Vec<u8>> {
+    args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+}
+
+fn get(db: &Db<SSTableEngine>, key: &str) -> Result<String, DbError> {
+    db.handle_get(&args(&["GET", key]))
+        .map(|value| String::from_utf8(value).unwrap())
+}
+
+#[test]
+fn nx_sees_keys_that_are_only_in_sstables() {
+    let db = open(&temp_dir("nx-sstable"));
+    db.handle_set(&args(&["SET", "k", "old"])).unwrap();
+    flush(&db);
+    assert!(db.data.read().unwrap().get(b"k").is_none());
+
+    let result = db.handle_set(&args(&["SET", "k", "new", "NX"]));
+    assert!(
+        matches!(result, Err(DbError::ConditionFailed(_))),
+        "{:?}",
+        result
+    );
+    assert_eq!(get(&db, "k").unwrap(), "old");
+
+    db.handle_set(&args(&["SET", "other", "v", "NX"])).unwrap();
+    assert_eq!(get(&db, "other").unwrap(), "v");
+}
+
+#[test]
+fn xx_fails_on_a_deleted_key() {
+    let db = open(&temp_dir("xx-tombstone"));
+    db.handle_set(&args(&["SET", "k", "old"])).unwrap();
+    flush(&db);
+    db.handle_delete(&args(&["DELETE", "k"])).unwrap();
+
+    // The SSTable still holds the old value, under the memtable's tombstone
+    let result = db.handle_set(&args(&["SET", "k", "new", "XX"]));
+    assert!(
+        matches!(result, Err(DbError::ConditionFailed(_))),
+        "{:?}",
+        result
+    );
+    assert!(matches!(get(&db, "k"), Err(DbError::Deleted(_))));
+
+    // Same once the tombstone is flushed too
+    flush(&db);
+    let result = db.handle_set(&args(&["SET", "k", "new", "XX"]));
+    assert!(
+        matches!(result, Err(DbError::ConditionFailed(_))),
+        "{:?}",
+        result
+    );
+
+    db.handle_set(&args(&["SET", "k", "new", "NX"])).unwrap();
+    db.handle_set(&args(&["SET", "k", "newer", "XX"])).unwrap();
+    assert_eq!(get(&db, "k").unwrap(), "newer");
+}
+
+#[test]
+fn cas_swaps_only_the_expected_value() {
+    let db = open(&temp_dir("cas"));
+    let result = db.handle_cas(&args(&["CAS", "k", "a", "b"]));
+    assert!(
+        matches!(result, Err(DbError::ConditionFailed(_))),
+        "{:?}",
+        result
+    );
+
+    db.handle_set(&args(&["SET", "k", "a"])).unwrap();
+    flush(&db);
+    let result = db.handle_cas(&args(&["CAS", "k", "x", "b"]));
+    assert!(
+        matches!(result, Err(DbError::ConditionFailed(_))),
+        "{:?}",
+        result
+    );
+    assert_eq!(get(&db, "k").unwrap(), "a");
+
+    db.handle_cas(&args(&["CAS", "k", "a", "b"])).unwrap();
+    assert_eq!(get(&db, "k").unwrap(), "b");
+    let result = db.handle_cas(&args(&["CAS", "k", "a", "c"]));
+    assert!(
+        matches!(result, Err(DbError::ConditionFailed(_))),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn exactly_one_nx_writer_wins() {
+    let db = Arc::new(open(&temp_dir("nx-race")));
+    for round in 0..20 {
+        let key = format!("lock{}", round);
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let db = db.clone();
+                let key = key.clone();
+                thread::spawn(move || {
+                    let value = format!("writer{}", writer);
+                    match db.handle_set(&args(&["SET", &key, &value, "NX"])) {
+                        Ok(_) => Some(value),
+                        Err(DbError::ConditionFailed(_)) => None,
+                        Err(e) => panic!("{:?}", e),
+                    }
+                })
+            })
+            .collect();
+        let winners: Vec<String> = writers
+            .into_iter()
+            .filter_map(|writer| writer.join().unwrap())
+            .collect();
+        assert_eq!(winners.len(), 1, "{}: {:?}", key, winners);
+        assert_eq!(get(&db, &key).unwrap(), winners[0]);
+    }
+}
+
+fn incr(db: &Db<SSTableEngine>, key: &str) -> Result<i64, DbError> {
+    db.handle_incr(&args(&["INCR", key]))
+        .map(|(value, _)| value)
+}
+
+#[test]
+fn incr_rejects_values_that_are_not_integers() {
+    let db = open(&temp_dir("incr-not-integer"));
+    for value in ["+5", "abc", " 5", "5.0", ""] {
+        db.handle_set(&args(&["SET", "k", value])).unwrap();
+        let result = incr(&db, "k");
+        assert!(
+            matches!(result, Err(DbError::NotAnInteger(_))),
+            "{:?}: {:?}",
+            value,
+            result
+        );
+        assert_eq!(get(&db, "k").unwrap(), value);
+    }
+
+    let result = db.handle_incr_by(&args(&["INCRBY", "n", "+1"]));
+    assert!(
+        matches!(result, Err(DbError::InvalidCommand(_))),
+        "{:?}",
+        result
+    );
+    assert_eq!(
+        db.handle_incr_by(&args(&["INCRBY", "n", "-3"])).unwrap().0,
+        -3
+    );
+    assert_eq!(db.handle_decr(&args(&["DECR", "n"])).unwrap().0, -4);
+}
+
+#[test]
+fn incr_fails_on_overflow_and_keeps_the_value() {
+    let db = open(&temp_dir("incr-overflow"));
+    db.handle_set(&args(&["SET", "max", &i64::MAX.to_string()]))
+        .unwrap();
+    flush(&db);
+    let result = incr(&db, "max");
+    assert!(matches!(result, Err(DbError::Overflow(_))), "{:?}", result);
+    assert_eq!(get(&db, "max").unwrap(), i64::MAX.to_string());
+
+    db.handle_set(&args(&["SET", "min", &i64::MIN.to_string()]))
+        .unwrap();
+    let result = db.handle_decr(&args(&["DECR", "min"]));
+    assert!(matches!(result, Err(DbError::Overflow(_))), "{:?}", result);
+    let result = db.handle_incr_by(&args(&["INCRBY", "min", &i64::MIN.to_string()]));
+    assert!(matches!(result, Err(DbError::Overflow(_))), "{:?}", result);
+}
+
+#[test]
+fn incr_by_float_rejects_non_finite_numbers() {
+    let db = open(&temp_dir("incr-float"));
+    for delta in ["inf", "-inf", "nan", "infinity", "+1.5", "abc"] {
+        let result = db.handle_incr_by_float(&args(&["INCRBYFLOAT", "f", delta]));
+        assert!(
+            matches!(result, Err(DbError::InvalidCommand(_))),
+            "{:?}: {:?}",
+            delta,
+            result
+        );
+    }
+    assert!(matches!(get(&db, "f"), Err(DbError::KeyNotFound(_))));
+
+    db.handle_set(&args(&["SET", "f", "nan"])).unwrap();
+    let result = db.handle_incr_by_float(&args(&["INCRBYFLOAT", "f", "1"]));
+    assert!(matches!(result, Err(DbError::NotAFloat(_))), "{:?}", result);
+
+    db.handle_set(&args(&["SET", "f", &f64::MAX.to_string()]))
+        .unwrap();
+    let result = db.handle_incr_by_float(&args(&["INCRBYFLOAT", "f", &f64::MAX.to_string()]));
+    assert!(matches!(result, Err(DbError::Overflow(_))), "{:?}", result);
+    assert_eq!(get(&db, "f").unwrap(), f64::MAX.to_string());
+
+    db.handle_set(&args(&["SET", "f", "1.5"])).unwrap();
+    let (value, _) = db
+        .handle_incr_by_float(&args(&["INCRBYFLOAT", "f", "0.25"]))
+        .unwrap();
+    assert_eq!(value, b"1.75");
+}
+
+#[test]
+fn concurrent_incrs_are_not_lost() {
+    let db = Arc::new(open(&temp_dir("incr-concurrent")));
+    let writers: Vec<_> = (0..8)
+        .map(|_| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for _ in 0..250 {
+                    incr(&db, "counter").unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    assert_eq!(get(&db, "counter").unwrap(), "2000");
+}
+
+fn ttl(db: &Db<SSTableEngine>, key: &str) -> i64 {
+    db.handle_ttl(&args(&["TTL", key])).unwrap()
+}
+
+#[test]
+fn expiry_survives_wal_replay() {
+    let dir = temp_dir("ttl-replay");
+    {
+        let db = open(&dir);
+        db.handle_set(&args(&["SET", "session", "abc", "EX", "100"]))
+            .unwrap();
+        db.handle_set(&args(&["SET", "gone", "abc", "PX", "1"]))
+            .unwrap();
+    }
+    thread::sleep(Duration::from_millis(5));
+
+    let db = open(&dir);
+    assert!((99..=100).contains(&ttl(&db, "session")));
+    assert_eq!(get(&db, "session").unwrap(), "abc");
+    assert_eq!(ttl(&db, "gone"), -2);
+    assert!(matches!(get(&db, "gone"), Err(DbError::Deleted(_))));
+}
+
+#[test]
+fn expiry_survives_a_flush() {
+    let dir = temp_dir("ttl-flush");
+    {
+        let db = open(&dir);
+        db.handle_set(&args(&["SET", "session", "abc", "EX", "100"]))
+            .unwrap();
+        db.handle_set(&args(&["SET", "gone", "abc", "PX", "1"]))
+            .unwrap();
+        flush(&db);
+        assert!(db.data.read().unwrap().data.is_empty());
+        thread::sleep(Duration::from_millis(5));
+        assert!((99..=100).contains(&ttl(&db, "session")));
+        assert_eq!(ttl(&db, "gone"), -2);
+    }
+
+    let db = open(&dir);
+    assert!((99..=100).contains(&ttl(&db, "session")));
+    assert!(matches!(get(&db, "gone"), Err(DbError::Deleted(_))));
+}
+
+#[test]
+fn sweep_replaces_expired_values_with_deletes() {
+    let dir = temp_dir("ttl-sweep");
+    {
+        let db = open(&dir);
+        db.handle_set(&args(&["SET", "gone", "abc", "PX", "1"]))
+            .unwrap();
+        db.handle_set(&args(&["SET", "kept", "abc", "EX", "100"]))
+            .unwrap();
+        thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(db.sweep_expired().unwrap(), 1);
+        assert!(matches!(
+            db.data.read().unwrap().get(b"gone"),
+            Some(Entry::Delete { .. })
+        ));
+        assert!(matches!(
+            db.data.read().unwrap().get(b"kept"),
+            Some(Entry::Put { .. })
+        ));
+        assert_eq!(db.sweep_expired().unwrap(), 0);
+    }
+
+    // The delete went through the WAL like any other write
+    let db = open(&dir);
+    assert!(matches!(
+        db.data.read().unwrap().get(b"gone"),
+        Some(Entry::Delete { .. })
+    ));
+}
+
+#[test]
+fn persist_clears_a_flushed_expiry() {
+    let dir = temp_dir("ttl-persist");
+    {
+        let db = open(&dir);
+        db.handle_set(&args(&["SET", "session", "abc", "EX", "100"]))
+            .unwrap();
+        flush(&db);
+
+        assert!(
+            db.handle_persist(&args(&["PERSIST", "session"]))
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(ttl(&db, "session"), -1);
+        assert!(
+            db.handle_persist(&args(&["PERSIST", "session"]))
+                .unwrap()
+                .is_none()
+        );
+        flush(&db);
+        assert_eq!(ttl(&db, "session"), -1);
+    }
+
+    let db = open(&dir);
+    assert_eq!(ttl(&db, "session"), -1);
+    assert_eq!(get(&db, "session").unwrap(), "abc");
+}
+
+#[test]
+fn reads_and_writes_keep_going_during_flushes_and_compaction() {
+    let db = Arc::new(open_with(
+        &temp_dir("concurrent"),
+        &SSTableConfig {
+            target_file_size_bytes: 2 * 1024,
+            ..SSTableConfig::default()
+        },
+        &CompactionConfig {
+            strategy: CompactionStrategyKind::Leveled,
+            level0_file_trigger: 2,
+            level_base_bytes: 4 * 1024,
+            level_size_ratio: 2,
+            ..CompactionConfig::default()
+        },
+        &MemtableConfig {
+            size_limit_bytes: 2 * 1024,
+        },
+    ));
+    let done = Arc::new(AtomicBool::new(false));
+
+    // Does what the flusher does, so writers that fill the memtable faster
+    // flush for themselves while a compaction is running
+    let flusher = {
+        let db = db.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(Ordering::Acquire) {
+                while db
+                    .immutables
+                    .flush_oldest(db.engine.as_ref(), &db.wal)
+                    .unwrap()
+                {}
+                db.engine.compact_sstables().unwrap();
+            }
+        })
+    };
+
+    // Each writer owns its keys and publishes how many it has written, so
+    // readers know which ones must be there
+    let written: Arc<Vec<AtomicUsize>> = Arc::new((0..4).map(|_| AtomicUsize::new(0)).collect());
+    let writers: Vec<_> = (0..4)
+        .map(|writer| {
+            let db = db.clone();
+            let written = written.clone();
+            thread::spawn(move || {
+                for i in 0..500 {
+                    let key = format!("w{}-{:04}", writer, i);
+                    db.handle_set(&args(&["SET", &key, &format!("{:0>64}", i)]))
+                        .unwrap();
+                    written[writer].store(i + 1, Ordering::Release);
+                }
+            })
+        })
+        .collect();
+    let readers: Vec<_> = (0..4)
+        .map(|reader| {
+            let db = db.clone();
+            let written = written.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut reads = 0;
+                while !done.load(Ordering::Acquire) {
+                    let writer = (reads + reader) % 4;
+                    let count = written[writer].load(Ordering::Acquire);
+                    if count > 0 {
+                        let i = reads * 7 % count;
+                        let key = format!("w{}-{:04}", writer, i);
+                        assert_eq!(get(&db, &key).unwrap(), format!("{:0>64}", i), "{}", key);
+                    }
+                    reads += 1;
+                }
+            })
+        })
+        .collect();
+
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    done.store(true, Ordering::Release);
+    flusher.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    assert!(!db.engine.stats().contains(&("compactions", 0)));
+    for writer in 0..4 {
+        for i in 0..500 {
+            let key = format!("w{}-{:04}", writer, i);
+            assert_eq!(get(&db, &key).unwrap(), format!("{:0>64}", i));
+        }
+    }
+}
@@ -1,14 +1,36 @@
+pub mod table;
+
+#[cfg(test)]
+mod tests;
+
 use std::{
     collections::BTreeMap,
-    fs::File,
-    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
 };
 
-use crate::common::db_errors::DbError;
+use crate::{
+    bloom::{self, BloomFilter},
+    common::{db_errors::DbError, entry::Entry, fs::sync_dir},
+};
 
 const MAGIC_HEADER: &[u8; 8] = b"MINIDBSS";
 const MAGIC_FOOTER: &[u8; 8] = b"MINIDIDX";
-const VERSION: u8 = 1;
+/// v1: one index entry per key. v2: data blocks with a sparse block index.
+/// v3: v2 with CRC32C checksums. v4: v3 with expiry times on values.
+const VERSION: u8 = 4;
+const VERSION_V3: u8 = 3;
+const VERSION_V2: u8 = 2;
+const VERSION_V1: u8 = 1;
+const HEADER_LEN: u64 = 16;
+const CHECKSUM_LEN: usize = 4;
+
+/// Header flag (first reserved byte): the file has a bloom filter block.
+const FLAG_BLOOM: u8 = 1;
+
+/// Data blocks are closed once they reach this many bytes.
+pub const BLOCK_SIZE: usize = 4 * 1024;
 
 // Write a u32 in big-endian format
 pub fn write_u32_be(writer: &mut impl Write, value: u32) -> std::io::Result<()> {
@@ -20,223 +42,263 @@ pub fn write_u64_be(writer: &mut impl Write, value: u64) -> std::io::Result<()>
     writer.write_all(&value.to_be_bytes())
 }
 
-/// Write a BTreeMap to a binary SSTable file
+/// Write a memtable to a binary SSTable file (format v4). Deletes are
+/// written as tombstones.
+///
 /// File format:
 /// - Header (16 bytes):
 ///   - Magic (8 bytes): "MINIDBSS"
 ///   - Version (1 byte)
-///   - Reserved (7 bytes)
-/// - Data section:
+///   - Flags (1 byte): bit 0 set if there is a bloom filter block
+///   - Reserved (2 bytes)
+///   - Header checksum (u32 BE): CRC32C of the 12 bytes above
+/// - Data blocks, each about `BLOCK_SIZE` bytes of records:
 ///   For each record:
 ///   - key_len (u32 BE)
 ///   - key (bytes)
-///   - tombstone (u8): 0=present, 1=deleted
+///   - kind (u8): 0=value, 1=tombstone, 2=value with an expiry time
+///   - expires_at (u64 BE, Unix milliseconds) - only if kind 2
 ///   - value_len (u32 BE) - only if not tombstone
 ///   - value (bytes) - only if not tombstone
-/// - Index section:
-///   For each key:
-///   - key_len (u32 BE)
-///   - key (bytes)
+///
+///   followed by the block checksum (u32 BE): CRC32C of the records
+/// - Index section (sparse, one entry per data block):
+///   For each block:
+///   - last_key_len (u32 BE)
+///   - last_key (bytes)
 ///   - offset (u64 BE)
+///   - size (u32 BE), not counting the block checksum
+/// - Bloom filter block (only if flagged), see `BloomFilter::encode`
 /// - Footer:
+///   - bloom_offset (u64 BE) - only if flagged
 ///   - index_offset (u64 BE)
+///   - Index checksum (u32 BE): CRC32C of the index and bloom filter block
+///   - Footer checksum (u32 BE): CRC32C of the footer fields above
 ///   - Magic (8 bytes): "MINIDIDX"
+///
+/// Version 3 files have no expiry times, so no records of kind 2. Version 2
+/// files also have no checksums: 6 reserved header bytes, no block
+/// checksums and a footer of just the offsets and magic. Version 1 files
+/// also have no blocks: the index has one `key_len, key, offset` entry per
+/// record.
+///
+/// `bloom_bits_per_key` of 0 writes no bloom filter.
 pub fn write_btree_to_binary_file(
-    map: &BTreeMap<String, String>,
+    map: &BTreeMap<Vec<u8>, Entry>,
     file_path: &str,
+    bloom_bits_per_key: usize,
 ) -> Result<(), DbError> {
-    let mut index_entries: Vec<(String, u64)> = Vec::new();
-
-    // Open file with BufWriter for efficient writing
-    let file = File::create(file_path)
-        .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to create file: {}", e)))?;
-    let mut writer = BufWriter::new(file);
-
-    // Write header
-    writer
-        .write_all(MAGIC_HEADER)
-        .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to write header magic: {}", e)))?;
-    writer
-        .write_all(&[VERSION])
-        .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to write version: {}", e)))?;
-    writer
-        .write_all(&[0; 7]) // Reserved bytes
-        .map_err(|e| {
-            DbError::SSTableWriteFailed(format!("Failed to write reserved bytes: {}", e))
-        })?;
+    let mut writer = SSTableWriter::create(file_path, bloom_bits_per_key)?;
+    for (key, entry) in map {
+        writer.add(key, entry.value(), entry.expires_at())?;
+    }
+    writer.finish()?;
+    Ok(())
+}
 
-    // Write data section and collect index entries
-    for (key, value) in map {
-        // Record the offset for this key
-        let record_offset = writer
-            .stream_position()
-            .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to get position: {}", e)))?;
-        index_entries.push((key.clone(), record_offset));
-
-        // Write key length and key
-        write_u32_be(&mut writer, key.len() as u32).map_err(|e| {
-            DbError::SSTableWriteFailed(format!("Failed to write key length: {}", e))
-        })?;
-        writer
-            .write_all(key.as_bytes())
-            .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to write key: {}", e)))?;
-
-        // Check if value is a tombstone
-        let is_tombstone = value.contains("___________TOMBSTONE________________");
-        if is_tombstone {
-            writer
-                .write_all(&[1]) // Tombstone flag
-                .map_err(|e| {
-                    DbError::SSTableWriteFailed(format!("Failed to write tombstone flag: {}", e))
-                })?;
+/// Streams records, in ascending key order, into a new SSTable in the format
+/// described on `write_btree_to_binary_file`. Only the current data block,
+/// the block index and one hash per key for the bloom filter are kept in
+/// memory.
+///
+/// The table is written to `{file_path}.tmp` and only renamed into place by
+/// `finish`, after an fsync, so `file_path` is never seen half-written. The
+/// temp file is removed if the writer is dropped unfinished.
+pub struct SSTableWriter {
+    file_path: String,
+    tmp_path: String,
+    installed: bool,
+    writer: BufWriter<File>,
+    bloom_bits_per_key: usize,
+    key_hashes: Vec<u64>,
+    block: Vec<u8>,
+    index_entries: Vec<(Vec<u8>, u64, u32)>,
+    /// Bytes written to the file so far, not counting `block`.
+    offset: u64,
+    first_key: Option<Vec<u8>>,
+    last_key: Option<Vec<u8>>,
+}
+
+impl SSTableWriter {
+    /// Creates the file and writes its header. `bloom_bits_per_key` of 0
+    /// writes no bloom filter.
+    pub fn create(file_path: &str, bloom_bits_per_key: usize) -> Result<Self, DbError> {
+        // Open file with BufWriter for efficient writing
+        let tmp_path = format!("{}.tmp", file_path);
+        let file = File::create(&tmp_path)
+            .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to create file: {}", e)))?;
+        let mut writer = BufWriter::new(file);
+
+        // Write header
+        let flags = if bloom_bits_per_key > 0 {
+            FLAG_BLOOM
         } else {
-            writer
-                .write_all(&[0]) // Not tombstone
-                .map_err(|e| {
-                    DbError::SSTableWriteFailed(format!("Failed to write tombstone flag: {}", e))
-                })?;
-            write_u32_be(&mut writer, value.len() as u32).map_err(|e| {
-                DbError::SSTableWriteFailed(format!("Failed to write value length: {}", e))
-            })?;
-            writer.write_all(value.as_bytes()).map_err(|e| {
-                DbError::SSTableWriteFailed(format!("Failed to write value: {}", e))
-            })?;
+            0
+        };
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(MAGIC_HEADER);
+        header.extend_from_slice(&[VERSION, flags, 0, 0]); // 2 reserved bytes
+        header.extend_from_slice(&crc32c::crc32c(&header).to_be_bytes());
+        writer
+            .write_all(&header)
+            .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to write header: {}", e)))?;
+
+        Ok(SSTableWriter {
+            file_path: file_path.to_string(),
+            tmp_path,
+            installed: false,
+            writer,
+            bloom_bits_per_key,
+            key_hashes: Vec::new(),
+            block: Vec::with_capacity(BLOCK_SIZE),
+            index_entries: Vec::new(),
+            offset: HEADER_LEN,
+            first_key: None,
+            last_key: None,
+        })
+    }
+
+    /// Appends a record; `None` writes a tombstone. A value expires at
+    /// `expires_at` if set. Keys must be added in ascending order.
+    pub fn add(
+        &mut self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        expires_at: Option<u64>,
+    ) -> Result<(), DbError> {
+        encode_record(&mut self.block, key, value, expires_at);
+        if self.bloom_bits_per_key > 0 {
+            self.key_hashes.push(bloom::key_hash(key));
+        }
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_vec());
+        }
+        self.last_key = Some(key.to_vec());
+
+        if self.block.len() >= BLOCK_SIZE {
+            self.flush_block()?;
         }
+        Ok(())
     }
 
-    // Write index section
-    let index_offset = writer
-        .stream_position()
-        .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to get index position: {}", e)))?;
+    /// Approximate size of the file so far.
+    pub fn bytes_written(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
 
-    // Write each index entry
-    for (key, offset) in index_entries {
-        write_u32_be(&mut writer, key.len() as u32).map_err(|e| {
-            DbError::SSTableWriteFailed(format!("Failed to write index key length: {}", e))
-        })?;
-        writer.write_all(key.as_bytes()).map_err(|e| {
-            DbError::SSTableWriteFailed(format!("Failed to write index key: {}", e))
+    pub fn first_key(&self) -> Option<&[u8]> {
+        self.first_key.as_deref()
+    }
+
+    pub fn last_key(&self) -> Option<&[u8]> {
+        self.last_key.as_deref()
+    }
+
+    /// Write the data block being built, followed by its checksum.
+    fn flush_block(&mut self) -> Result<(), DbError> {
+        let Some(last_key) = &self.last_key else {
+            return Ok(());
+        };
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        self.writer.write_all(&self.block).map_err(|e| {
+            DbError::SSTableWriteFailed(format!("Failed to write data block: {}", e))
         })?;
-        write_u64_be(&mut writer, offset).map_err(|e| {
-            DbError::SSTableWriteFailed(format!("Failed to write index offset: {}", e))
+        write_u32_be(&mut self.writer, crc32c::crc32c(&self.block)).map_err(|e| {
+            DbError::SSTableWriteFailed(format!("Failed to write block checksum: {}", e))
         })?;
+        self.index_entries
+            .push((last_key.clone(), self.offset, self.block.len() as u32));
+        self.offset += (self.block.len() + CHECKSUM_LEN) as u64;
+        self.block.clear();
+        Ok(())
     }
 
-    // Write footer
-    write_u64_be(&mut writer, index_offset)
-        .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to write index offset: {}", e)))?;
-    writer
-        .write_all(MAGIC_FOOTER)
-        .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to write footer magic: {}", e)))?;
+    /// Writes the last block, the index, the bloom filter and the footer,
+    /// then fsyncs the file and renames it into place. Returns the size of
+    /// the file.
+    pub fn finish(mut self) -> Result<u64, DbError> {
+        self.flush_block()?;
+        let index_offset = self.offset;
 
-    // Ensure all data is written to disk
-    writer
-        .flush()
-        .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to flush writer: {}", e)))?;
+        // Index and bloom filter are built in memory so they can share a checksum
+        let mut meta = Vec::new();
+        for (key, offset, size) in &self.index_entries {
+            meta.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            meta.extend_from_slice(key);
+            meta.extend_from_slice(&offset.to_be_bytes());
+            meta.extend_from_slice(&size.to_be_bytes());
+        }
 
-    Ok(())
-}
+        let mut footer = Vec::new();
+        if self.bloom_bits_per_key > 0 {
+            let mut bloom = BloomFilter::new(self.key_hashes.len(), self.bloom_bits_per_key);
+            for hash in &self.key_hashes {
+                bloom.add_hash(*hash);
+            }
+            let bloom_offset = index_offset + meta.len() as u64;
+            meta.extend_from_slice(&bloom.encode());
+            footer.extend_from_slice(&bloom_offset.to_be_bytes());
+        }
+        footer.extend_from_slice(&index_offset.to_be_bytes());
+        footer.extend_from_slice(&crc32c::crc32c(&meta).to_be_bytes());
+        footer.extend_from_slice(&crc32c::crc32c(&footer).to_be_bytes());
+        footer.extend_from_slice(MAGIC_FOOTER);
+
+        self.writer
+            .write_all(&meta)
+            .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to write index: {}", e)))?;
+        self.writer
+            .write_all(&footer)
+            .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to write footer: {}", e)))?;
+
+        // Ensure all data is written to disk
+        self.writer
+            .flush()
+            .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to flush writer: {}", e)))?;
+        self.writer
+            .get_ref()
+            .sync_all()
+            .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to sync file: {}", e)))?;
 
-/// Read a single key from a binary SSTable file and return its value as a UTF-8 string.
-/// If the key is marked as tombstone or not found, returns `DbError::KeyNotFound`.
-pub fn read_key_from_binary_file(file_path: &str, search_key: &str) -> Result<String, DbError> {
-    let mut file = File::open(file_path).map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-    let metadata = file
-        .metadata()
-        .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-    let file_len = metadata.len();
-
-    // Footer is 8 (u64 index_offset) + 8 (magic)
-    if file_len < 16 {
-        return Err(DbError::SSTableReadFailed(
-            "sstable file too small".to_string(),
-        ));
+        fs::rename(&self.tmp_path, &self.file_path)
+            .map_err(|e| DbError::SSTableWriteFailed(format!("Failed to install file: {}", e)))?;
+        self.installed = true;
+        if let Some(dir) = Path::new(&self.file_path).parent().and_then(|d| d.to_str()) {
+            sync_dir(if dir.is_empty() { "." } else { dir })?;
+        }
+
+        Ok(index_offset + (meta.len() + footer.len()) as u64)
     }
+}
 
-    // Seek to footer
-    file.seek(SeekFrom::Start(file_len - 16))
-        .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-
-    let mut buf8 = [0u8; 8];
-    file.read_exact(&mut buf8)
-        .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-    let index_offset = u64::from_be_bytes(buf8);
-
-    let mut magic = [0u8; 8];
-    file.read_exact(&mut magic)
-        .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-    if &magic != MAGIC_FOOTER {
-        return Err(DbError::SSTableReadFailed(
-            "invalid sstable footer magic".to_string(),
-        ));
+impl Drop for SSTableWriter {
+    fn drop(&mut self) {
+        if !self.installed {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
     }
+}
 
-    // Seek to index and scan entries to find the key
-    file.seek(SeekFrom::Start(index_offset))
-        .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-
-    // The index runs until footer (file_len - 16)
-    let index_end = file_len - 16;
-
-    while file
-        .stream_position()
-        .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?
-        < index_end
-    {
-        // read key_len
-        let mut key_len_buf = [0u8; 4];
-        file.read_exact(&mut key_len_buf)
-            .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-        let key_len = u32::from_be_bytes(key_len_buf) as usize;
-
-        let mut key_buf = vec![0u8; key_len];
-        file.read_exact(&mut key_buf)
-            .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-        let key_str = String::from_utf8(key_buf.clone())
-            .map_err(|e| DbError::SSTableReadFailed(format!("invalid utf8 in index key: {}", e)))?;
-
-        // read offset
-        let mut off_buf = [0u8; 8];
-        file.read_exact(&mut off_buf)
-            .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-        let record_offset = u64::from_be_bytes(off_buf);
-
-        if key_str == search_key {
-            // Found index entry. Seek to record and read it.
-            file.seek(SeekFrom::Start(record_offset))
-                .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-
-            // read record key_len and key (we can skip validating but do it to advance cursor)
-            let mut rec_key_len_buf = [0u8; 4];
-            file.read_exact(&mut rec_key_len_buf)
-                .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-            let rec_key_len = u32::from_be_bytes(rec_key_len_buf) as usize;
-            let mut rec_key_buf = vec![0u8; rec_key_len];
-            file.read_exact(&mut rec_key_buf)
-                .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-
-            // tombstone flag
-            let mut tomb_buf = [0u8; 1];
-            file.read_exact(&mut tomb_buf)
-                .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-            if tomb_buf[0] == 1 {
-                return Err(DbError::TombStoneFound);
-            }
+/// Append one data record to `buf`; `None` is a tombstone.
+fn encode_record(buf: &mut Vec<u8>, key: &[u8], value: Option<&[u8]>, expires_at: Option<u64>) {
+    buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    buf.extend_from_slice(key);
 
-            // read value length and value
-            let mut val_len_buf = [0u8; 4];
-            file.read_exact(&mut val_len_buf)
-                .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-            let val_len = u32::from_be_bytes(val_len_buf) as usize;
-            let mut val_buf = vec![0u8; val_len];
-            file.read_exact(&mut val_buf)
-                .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-
-            let value = String::from_utf8(val_buf)
-                .map_err(|e| DbError::SSTableReadFailed(format!("invalid utf8 in value: {}", e)))?;
-            return Ok(value);
+    match value {
+        None => buf.push(1),
+        Some(value) => {
+            match expires_at {
+                None => buf.push(0),
+                Some(expires_at) => {
+                    buf.push(2);
+                    buf.extend_from_slice(&expires_at.to_be_bytes());
+                }
+            }
+            buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            buf.extend_from_slice(value);
         }
     }
-
-    Err(DbError::KeyNotInFile)
 }
@@ -0,0 +1,587 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+use crate::{
+    bloom::BloomFilter,
+    cache::BlockCache,
+    common::{db_errors::DbError, entry},
+    ende::{
+        BLOCK_SIZE, CHECKSUM_LEN, FLAG_BLOOM, HEADER_LEN, MAGIC_FOOTER, MAGIC_HEADER, VERSION,
+        VERSION_V1, VERSION_V2, VERSION_V3,
+    },
+};
+
+/// A key and its value, `None` for a tombstone.
+pub type Record = (Vec<u8>, Option<StoredValue>);
+
+/// A value as stored in a table, with the time it expires at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredValue {
+    pub data: Vec<u8>,
+    /// Unix milliseconds; `None` never expires.
+    pub expires_at: Option<u64>,
+}
+
+impl StoredValue {
+    pub fn is_expired(&self, now_millis: u64) -> bool {
+        entry::is_expired(self.expires_at, now_millis)
+    }
+}
+
+/// Smallest and largest key of a table.
+pub type KeyRange = (Vec<u8>, Vec<u8>);
+
+/// Location of one data block, keyed by the last key it holds. `size` doesn't
+/// include the checksum that follows the block in v3 and later files.
+pub struct BlockHandle {
+    pub last_key: Vec<u8>,
+    pub offset: u64,
+    pub size: u32,
+}
+
+pub enum TableIndex {
+    /// v1: every key with the offset of its record.
+    Dense(Vec<(Vec<u8>, u64)>),
+    /// v2 and v3: one entry per data block.
+    Blocks(Vec<BlockHandle>),
+}
+
+/// An SSTable with its index and bloom filter loaded in memory, so a lookup
+/// is a binary search plus one read from disk.
+pub struct Table {
+    /// File name, which identifies the table in the block cache.
+    pub id: String,
+    pub file_path: String,
+    pub version: u8,
+    pub index: TableIndex,
+    pub bloom: Option<BloomFilter>,
+    /// Where the data section ends and the index starts.
+    data_end: u64,
+}
+
+/// A damaged byte range found by `verify`.
+#[derive(Debug)]
+pub struct Damage {
+    pub offset: u64,
+    pub len: u64,
+    pub reason: String,
+}
+
+/// Where the sections of an SSTable are, from its header and footer.
+struct Layout {
+    version: u8,
+    index_offset: u64,
+    bloom_offset: Option<u64>,
+    /// End of the index and bloom block, where the footer starts.
+    meta_end: u64,
+    /// CRC32C of the index and bloom block (v3 only).
+    meta_crc: Option<u32>,
+}
+
+impl Table {
+    /// Read the header, footer, index and bloom filter of an SSTable.
+    pub fn open(file_path: &str) -> Result<Self, DbError> {
+        let mut file =
+            File::open(file_path).map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
+        let layout = read_layout(&mut file, file_path)?;
+
+        let meta = read_at(
+            &mut file,
+            layout.index_offset,
+            (layout.meta_end - layout.index_offset) as usize,
+        )?;
+        if let Some(crc) = layout.meta_crc
+            && crc32c::crc32c(&meta) != crc
+        {
+            return Err(corruption(file_path, layout.index_offset));
+        }
+
+        let index_len =
+            (layout.bloom_offset.unwrap_or(layout.meta_end) - layout.index_offset) as usize;
+        let (index_buf, bloom_buf) = meta.split_at(index_len);
+        let index = if layout.version == VERSION_V1 {
+            TableIndex::Dense(parse_dense_index(index_buf)?)
+        } else {
+            TableIndex::Blocks(parse_block_index(index_buf)?)
+        };
+
+        let bloom = match layout.bloom_offset {
+            Some(_) => Some(BloomFilter::decode(bloom_buf).ok_or_else(|| {
+                DbError::SSTableReadFailed("invalid bloom filter block".to_string())
+            })?),
+            None => None,
+        };
+
+        let id = std::path::Path::new(file_path)
+            .file_name()
+            .and_then(|f| f.to_str())
+            .unwrap_or(file_path)
+            .to_string();
+
+        Ok(Table {
+            id,
+            file_path: file_path.to_string(),
+            version: layout.version,
+            index,
+            bloom,
+            data_end: layout.index_offset,
+        })
+    }
+
+    /// False if the bloom filter rules the key out. Tables without a filter
+    /// may contain anything.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.bloom
+            .as_ref()
+            .is_none_or(|bloom| bloom.may_contain(key))
+    }
+
+    /// Look up a key, reading its block through `cache`. Returns
+    /// `DbError::TombStoneFound` if the key is deleted,
+    /// `DbError::KeyNotInFile` if the table doesn't hold it and
+    /// `DbError::Corruption` if the block fails its checksum.
+    pub fn get(&self, search_key: &[u8], cache: &BlockCache) -> Result<StoredValue, DbError> {
+        let Some((start, end)) = self.block_range(search_key) else {
+            return Err(DbError::KeyNotInFile);
+        };
+        let records = cache.block(&self.id, start, || self.read_block(start, end))?;
+
+        let mut pos = 0;
+        while pos < records.len() {
+            let ((key, value), len) = decode_record(&records[pos..])?;
+            if key == search_key {
+                return value.ok_or(DbError::TombStoneFound);
+            }
+            pos += len;
+        }
+
+        Err(DbError::KeyNotInFile)
+    }
+
+    /// Byte range of the only block (v1: record) that can hold `search_key`.
+    fn block_range(&self, search_key: &[u8]) -> Option<(u64, u64)> {
+        match &self.index {
+            TableIndex::Dense(entries) => {
+                let pos = entries
+                    .binary_search_by(|(key, _)| key.as_slice().cmp(search_key))
+                    .ok()?;
+                let start = entries[pos].1;
+                let end = entries.get(pos + 1).map_or(self.data_end, |(_, o)| *o);
+                Some((start, end))
+            }
+            TableIndex::Blocks(blocks) => {
+                // First block whose last key is >= the search key
+                let pos = blocks.partition_point(|b| b.last_key.as_slice() < search_key);
+                let block = blocks.get(pos)?;
+                Some((block.offset, block.offset + block.size as u64))
+            }
+        }
+    }
+
+    /// Approximate memory held by the index and bloom filter.
+    pub fn size_bytes(&self) -> usize {
+        let index: usize = match &self.index {
+            TableIndex::Dense(entries) => entries.iter().map(|(key, _)| key.len() + 32).sum(),
+            TableIndex::Blocks(blocks) => blocks.iter().map(|b| b.last_key.len() + 40).sum(),
+        };
+        let bloom = self.bloom.as_ref().map_or(0, |bloom| bloom.size_bytes());
+        index + bloom + self.file_path.len() + self.id.len()
+    }
+
+    /// Smallest and largest key in the table, or `None` if it is empty.
+    pub fn key_range(&self) -> Result<Option<KeyRange>, DbError> {
+        let largest = match &self.index {
+            TableIndex::Dense(entries) => entries.last().map(|(key, _)| key.clone()),
+            TableIndex::Blocks(blocks) => blocks.last().map(|b| b.last_key.clone()),
+        };
+        let Some(largest) = largest else {
+            return Ok(None);
+        };
+        let smallest = match self.iter()?.next() {
+            Some(record) => record?.0,
+            None => return Ok(None),
+        };
+        Ok(Some((smallest, largest)))
+    }
+
+    /// Read every record in key order. Deleted keys have a `None` value.
+    pub fn scan(&self) -> Result<Vec<Record>, DbError> {
+        self.iter()?.collect()
+    }
+
+    /// Iterate over the records in key order, reading one block at a time.
+    pub fn iter(&self) -> Result<TableIter, DbError> {
+        let ranges = match &self.index {
+            // v1 has no blocks, so read runs of records of about a block each
+            TableIndex::Dense(entries) => {
+                let mut ranges = Vec::new();
+                let mut start = HEADER_LEN;
+                for (_, offset) in entries {
+                    if *offset - start >= BLOCK_SIZE as u64 {
+                        ranges.push((start, *offset));
+                        start = *offset;
+                    }
+                }
+                if start < self.data_end {
+                    ranges.push((start, self.data_end));
+                }
+                ranges
+            }
+            TableIndex::Blocks(blocks) => blocks
+                .iter()
+                .map(|b| (b.offset, b.offset + b.size as u64))
+                .collect(),
+        };
+
+        Ok(TableIter {
+            file: File::open(&self.file_path)
+                .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?,
+            file_path: self.file_path.clone(),
+            checksummed: self.version >= VERSION_V3,
+            ranges: ranges.into_iter(),
+            block: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    /// Read the bytes in `start..end`, checking the block checksum that
+    /// follows them in v3 and later files.
+    fn read_block(&self, start: u64, end: u64) -> Result<Vec<u8>, DbError> {
+        let mut file =
+            File::open(&self.file_path).map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
+        read_block(
+            &mut file,
+            &self.file_path,
+            start,
+            end,
+            self.version >= VERSION_V3,
+        )
+    }
+}
+
+/// Records of one table in key order, from `Table::iter`. Holds a single
+/// block in memory.
+pub struct TableIter {
+    file: File,
+    file_path: String,
+    checksummed: bool,
+    ranges: std::vec::IntoIter<(u64, u64)>,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Record, DbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pos >= self.block.len() {
+            let (start, end) = self.ranges.next()?;
+            match read_block(
+                &mut self.file,
+                &self.file_path,
+                start,
+                end,
+                self.checksummed,
+            ) {
+                Ok(block) => {
+                    self.block = block;
+                    self.pos = 0;
+                }
+                Err(e) => {
+                    // Nothing after a damaged block is returned
+                    self.ranges = Vec::new().into_iter();
+                    self.block.clear();
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        match decode_record(&self.block[self.pos..]) {
+            Ok((record, len)) => {
+                self.pos += len;
+                Some(Ok(record))
+            }
+            Err(e) => {
+                self.ranges = Vec::new().into_iter();
+                self.block.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Read the bytes in `start..end` of `file`, checking the block checksum
+/// that follows them if `checksummed`.
+fn read_block(
+    file: &mut File,
+    file_path: &str,
+    start: u64,
+    end: u64,
+    checksummed: bool,
+) -> Result<Vec<u8>, DbError> {
+    if !checksummed {
+        return read_at(file, start, (end - start) as usize);
+    }
+
+    let mut block = read_at(file, start, (end - start) as usize + CHECKSUM_LEN)?;
+    let crc = be_u32(&block.split_off(block.len() - CHECKSUM_LEN));
+    if crc32c::crc32c(&block) != crc {
+        return Err(corruption(file_path, start));
+    }
+    Ok(block)
+}
+
+/// Check every checksum in an SSTable and return the damaged ranges. Only
+/// fails if the file can't be read at all.
+///
+/// v1 and v2 files have no checksums, so they are only checked for records
+/// that can't be decoded.
+pub fn verify(file_path: &str) -> Result<Vec<Damage>, DbError> {
+    let mut file = File::open(file_path).map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
+    let mut damage = Vec::new();
+
+    let layout = match read_layout(&mut file, file_path) {
+        Ok(layout) => layout,
+        Err(e) => {
+            // Without the header and footer nothing else can be located
+            damage.push(damage_from(e, "header or footer"));
+            return Ok(damage);
+        }
+    };
+
+    if layout.version < VERSION_V3 {
+        if let Err(e) = Table::open(file_path).and_then(|table| table.scan()) {
+            damage.push(damage_from(e, "unreadable records"));
+        }
+        return Ok(damage);
+    }
+
+    let meta_len = layout.meta_end - layout.index_offset;
+    let meta = read_at(&mut file, layout.index_offset, meta_len as usize)?;
+    if layout.meta_crc != Some(crc32c::crc32c(&meta)) {
+        damage.push(Damage {
+            offset: layout.index_offset,
+            len: meta_len,
+            reason: "index checksum mismatch".to_string(),
+        });
+    }
+
+    // A damaged index may still parse; if it doesn't, the blocks can't be found
+    let index_len = (layout.bloom_offset.unwrap_or(layout.meta_end) - layout.index_offset) as usize;
+    let blocks = match parse_block_index(&meta[..index_len]) {
+        Ok(blocks) => blocks,
+        Err(e) => {
+            damage.push(damage_from(e, "index"));
+            return Ok(damage);
+        }
+    };
+
+    for block in blocks {
+        let len = block.size as u64 + CHECKSUM_LEN as u64;
+        let ok = block.offset + len <= layout.index_offset
+            && read_at(&mut file, block.offset, len as usize)
+                .map(|buf| {
+                    let (data, crc) = buf.split_at(block.size as usize);
+                    crc32c::crc32c(data) == be_u32(crc)
+                })
+                .unwrap_or(false);
+        if !ok {
+            damage.push(Damage {
+                offset: block.offset,
+                len,
+                reason: format!(
+                    "data block checksum mismatch (last key {:?})",
+                    String::from_utf8_lossy(&block.last_key)
+                ),
+            });
+        }
+    }
+
+    Ok(damage)
+}
+
+fn damage_from(e: DbError, section: &str) -> Damage {
+    match e {
+        DbError::Corruption { offset, .. } => Damage {
+            offset,
+            len: 0,
+            reason: format!("{} checksum mismatch", section),
+        },
+        e => Damage {
+            offset: 0,
+            len: 0,
+            reason: format!("{}: {:?}", section, e),
+        },
+    }
+}
+
+fn corruption(file_path: &str, offset: u64) -> DbError {
+    DbError::Corruption {
+        file: file_path.to_string(),
+        offset,
+    }
+}
+
+/// Read and check the header and footer.
+fn read_layout(file: &mut File, file_path: &str) -> Result<Layout, DbError> {
+    let file_len = file
+        .metadata()
+        .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?
+        .len();
+
+    // Header plus the smallest footer: 8 (u64 index_offset) + 8 (magic)
+    if file_len < HEADER_LEN + 16 {
+        return Err(DbError::SSTableReadFailed(
+            "sstable file too small".to_string(),
+        ));
+    }
+
+    let header = read_at(file, 0, HEADER_LEN as usize)?;
+    if &header[..8] != MAGIC_HEADER {
+        return Err(corruption(file_path, 0));
+    }
+    let version = header[8];
+    if version != VERSION && version != VERSION_V3 && version != VERSION_V2 && version != VERSION_V1
+    {
+        return Err(DbError::SSTableReadFailed(format!(
+            "unsupported sstable version {}",
+            version
+        )));
+    }
+    if version >= VERSION_V3 && crc32c::crc32c(&header[..12]) != be_u32(&header[12..]) {
+        return Err(corruption(file_path, 0));
+    }
+    let has_bloom = header[9] & FLAG_BLOOM != 0;
+
+    // Footer: [bloom_offset] index_offset [index_crc footer_crc] magic
+    let mut footer_len = if has_bloom { 24 } else { 16 };
+    if version >= VERSION_V3 {
+        footer_len += 2 * CHECKSUM_LEN as u64;
+    }
+    if file_len < HEADER_LEN + footer_len {
+        return Err(corruption(file_path, 0));
+    }
+    let meta_end = file_len - footer_len;
+
+    let footer = read_at(file, meta_end, footer_len as usize)?;
+    let (body, magic) = footer.split_at(footer.len() - 8);
+    if magic != MAGIC_FOOTER {
+        return Err(corruption(file_path, meta_end));
+    }
+    let (body, meta_crc) = if version >= VERSION_V3 {
+        let (body, crcs) = body.split_at(body.len() - 2 * CHECKSUM_LEN);
+        let footer_crc = be_u32(&crcs[CHECKSUM_LEN..]);
+        if crc32c::crc32c(&footer[..footer.len() - 8 - CHECKSUM_LEN]) != footer_crc {
+            return Err(corruption(file_path, meta_end));
+        }
+        (body, Some(be_u32(crcs)))
+    } else {
+        (body, None)
+    };
+    let index_offset = be_u64(&body[body.len() - 8..]);
+    let bloom_offset = has_bloom.then(|| be_u64(body));
+
+    // The index runs until the bloom block or the footer
+    let index_end = bloom_offset.unwrap_or(meta_end);
+    if index_offset < HEADER_LEN || index_offset > index_end || index_end > meta_end {
+        return Err(corruption(file_path, meta_end));
+    }
+
+    Ok(Layout {
+        version,
+        index_offset,
+        bloom_offset,
+        meta_end,
+        meta_crc,
+    })
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> Result<Vec<u8>, DbError> {
+    let mut buf = vec![0u8; len];
+    file.seek(SeekFrom::Start(offset))
+        .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
+    file.read_exact(&mut buf)
+        .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
+    Ok(buf)
+}
+
+fn be_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn be_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// Take `len` bytes from `buf` at `pos`, or fail if the buffer is too short.
+fn take(buf: &[u8], pos: usize, len: usize) -> Result<&[u8], DbError> {
+    buf.get(pos..pos + len)
+        .ok_or_else(|| DbError::SSTableReadFailed("truncated sstable entry".to_string()))
+}
+
+/// Decode the record at the start of `buf`, returning its key, value (`None`
+/// for a tombstone) and encoded length.
+fn decode_record(buf: &[u8]) -> Result<(Record, usize), DbError> {
+    let key_len = be_u32(take(buf, 0, 4)?) as usize;
+    let key = take(buf, 4, key_len)?.to_vec();
+    let mut pos = 4 + key_len;
+
+    let kind = take(buf, pos, 1)?[0];
+    pos += 1;
+    let expires_at = match kind {
+        0 => None,
+        1 => return Ok(((key, None), pos)),
+        2 => {
+            let expires_at = be_u64(take(buf, pos, 8)?);
+            pos += 8;
+            Some(expires_at)
+        }
+        _ => {
+            return Err(DbError::SSTableReadFailed(format!(
+                "unknown sstable record kind {}",
+                kind
+            )));
+        }
+    };
+
+    let value_len = be_u32(take(buf, pos, 4)?) as usize;
+    let data = take(buf, pos + 4, value_len)?.to_vec();
+    pos += 4 + value_len;
+
+    Ok(((key, Some(StoredValue { data, expires_at })), pos))
+}
+
+fn parse_dense_index(buf: &[u8]) -> Result<Vec<(Vec<u8>, u64)>, DbError> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let key_len = be_u32(take(buf, pos, 4)?) as usize;
+        let key = take(buf, pos + 4, key_len)?.to_vec();
+        let offset = be_u64(take(buf, pos + 4 + key_len, 8)?);
+        entries.push((key, offset));
+        pos += 4 + key_len + 8;
+    }
+    Ok(entries)
+}
+
+fn parse_block_index(buf: &[u8]) -> Result<Vec<BlockHandle>, DbError> {
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let key_len = be_u32(take(buf, pos, 4)?) as usize;
+        let last_key = take(buf, pos + 4, key_len)?.to_vec();
+        let offset = be_u64(take(buf, pos + 4 + key_len, 8)?);
+        let size = be_u32(take(buf, pos + 4 + key_len + 8, 4)?);
+        blocks.push(BlockHandle {
+            last_key,
+            offset,
+            size,
+        });
+        pos += 4 + key_len + 8 + 4;
+    }
+    Ok(blocks)
+}
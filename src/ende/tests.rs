@@ -0,0 +1,251 @@
+use std::{collections::BTreeMap, fs};
+
+use crate::{
+    cache::BlockCache,
+    common::{db_errors::DbError, entry::Entry},
+    ende::{
+        BLOCK_SIZE, MAGIC_FOOTER, MAGIC_HEADER, VERSION, VERSION_V1, VERSION_V2,
+        table::{Table, TableIndex, verify},
+        write_btree_to_binary_file,
+    },
+    storage_engine::sstable_engine::verify_sstables,
+};
+
+/// Fresh directory under the system temp dir.
+fn temp_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("mdb-ende-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.to_string_lossy().into_owned()
+}
+
+/// A record as v1 and v2 files store it: no expiry times, so kind 0 or 1.
+fn legacy_record(key: &[u8], value: Option<&[u8]>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    buf.extend_from_slice(key);
+    match value {
+        Some(value) => {
+            buf.push(0);
+            buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            buf.extend_from_slice(value);
+        }
+        None => buf.push(1),
+    }
+    buf
+}
+
+/// Header of a v1 or v2 file: magic, version and 7 reserved bytes.
+fn legacy_header(version: u8) -> Vec<u8> {
+    let mut buf = MAGIC_HEADER.to_vec();
+    buf.push(version);
+    buf.extend_from_slice(&[0; 7]);
+    buf
+}
+
+fn legacy_footer(buf: &mut Vec<u8>, index_offset: u64) {
+    buf.extend_from_slice(&index_offset.to_be_bytes());
+    buf.extend_from_slice(MAGIC_FOOTER);
+}
+
+/// Records of `key0000`.. with every fifth one deleted.
+fn legacy_records(count: u32) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+    (0..count)
+        .map(|i| {
+            let key = format!("key{:04}", i).into_bytes();
+            let value = (i % 5 != 0).then(|| format!("value{}", i).into_bytes());
+            (key, value)
+        })
+        .collect()
+}
+
+/// A v1 file: the records, then a dense index with one entry per key.
+fn v1_table(records: &[(Vec<u8>, Option<Vec<u8>>)]) -> Vec<u8> {
+    let mut buf = legacy_header(VERSION_V1);
+    let mut index = Vec::new();
+    for (key, value) in records {
+        index.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        index.extend_from_slice(key);
+        index.extend_from_slice(&(buf.len() as u64).to_be_bytes());
+        buf.extend_from_slice(&legacy_record(key, value.as_deref()));
+    }
+    let index_offset = buf.len() as u64;
+    buf.extend_from_slice(&index);
+    legacy_footer(&mut buf, index_offset);
+    buf
+}
+
+/// A v2 file: blocks of `per_block` records without checksums, then a
+/// sparse index with one entry per block.
+fn v2_table(records: &[(Vec<u8>, Option<Vec<u8>>)], per_block: usize) -> Vec<u8> {
+    let mut buf = legacy_header(VERSION_V2);
+    let mut index = Vec::new();
+    for block in records.chunks(per_block) {
+        let offset = buf.len() as u64;
+        for (key, value) in block {
+            buf.extend_from_slice(&legacy_record(key, value.as_deref()));
+        }
+        let last_key = &block.last().unwrap().0;
+        index.extend_from_slice(&(last_key.len() as u32).to_be_bytes());
+        index.extend_from_slice(last_key);
+        index.extend_from_slice(&offset.to_be_bytes());
+        index.extend_from_slice(&((buf.len() as u64 - offset) as u32).to_be_bytes());
+    }
+    let index_offset = buf.len() as u64;
+    buf.extend_from_slice(&index);
+    legacy_footer(&mut buf, index_offset);
+    buf
+}
+
+/// Reads every key back through `get` and `scan` and checks it against
+/// `records`.
+fn check_table(table: &Table, records: &[(Vec<u8>, Option<Vec<u8>>)]) {
+    let cache = BlockCache::new(1024 * 1024);
+    for (key, value) in records {
+        match (table.get(key, &cache), value) {
+            (Ok(found), Some(value)) => {
+                assert_eq!(&found.data, value);
+                assert_eq!(found.expires_at, None);
+            }
+            (Err(DbError::TombStoneFound), None) => {}
+            (result, _) => panic!("{:?}: unexpected {:?}", key, result),
+        }
+    }
+    assert!(matches!(
+        table.get(b"key00005", &cache),
+        Err(DbError::KeyNotInFile)
+    ));
+    assert!(matches!(
+        table.get(b"zzz", &cache),
+        Err(DbError::KeyNotInFile)
+    ));
+
+    let scanned: Vec<_> = table
+        .scan()
+        .unwrap()
+        .into_iter()
+        .map(|(key, value)| (key, value.map(|v| v.data)))
+        .collect();
+    assert_eq!(scanned, records);
+    assert_eq!(
+        table.key_range().unwrap(),
+        Some((records[0].0.clone(), records.last().unwrap().0.clone()))
+    );
+}
+
+#[test]
+fn v1_and_v2_tables_are_read_by_the_current_reader() {
+    let dir = temp_dir("legacy");
+    // Enough records for several blocks' worth of v1 data
+    let records = legacy_records(600);
+
+    let path = format!("{}/v1.db", dir);
+    fs::write(&path, v1_table(&records)).unwrap();
+    let table = Table::open(&path).unwrap();
+    assert_eq!(table.version, VERSION_V1);
+    assert!(matches!(table.index, TableIndex::Dense(_)));
+    assert!(table.bloom.is_none());
+    check_table(&table, &records);
+    assert!(verify(&path).unwrap().is_empty());
+
+    let path = format!("{}/v2.db", dir);
+    fs::write(&path, v2_table(&records, 64)).unwrap();
+    let table = Table::open(&path).unwrap();
+    assert_eq!(table.version, VERSION_V2);
+    assert!(matches!(&table.index, TableIndex::Blocks(blocks) if blocks.len() == 10));
+    check_table(&table, &records);
+    assert!(verify(&path).unwrap().is_empty());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn current_tables_split_records_into_blocks() {
+    let dir = temp_dir("current");
+    let records = legacy_records(600);
+    let memtable: BTreeMap<Vec<u8>, Entry> = records
+        .iter()
+        .enumerate()
+        .map(|(seq, (key, value))| {
+            let seq = seq as u64 + 1;
+            let entry = match value {
+                Some(value) => Entry::Put {
+                    seq,
+                    value: value.clone(),
+                    expires_at: None,
+                },
+                None => Entry::Delete { seq },
+            };
+            (key.clone(), entry)
+        })
+        .collect();
+
+    let path = format!("{}/current.db", dir);
+    write_btree_to_binary_file(&memtable, &path, 10).unwrap();
+    let table = Table::open(&path).unwrap();
+    assert_eq!(table.version, VERSION);
+    let TableIndex::Blocks(blocks) = &table.index else {
+        panic!("no block index");
+    };
+    assert!(blocks.len() > 1);
+    assert!(blocks.iter().all(|b| b.size as usize <= 2 * BLOCK_SIZE));
+    check_table(&table, &records);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn verify_reports_every_damaged_block() {
+    let dir = temp_dir("verify");
+    let memtable: BTreeMap<Vec<u8>, Entry> = (0..600)
+        .map(|i| {
+            let entry = Entry::Put {
+                seq: 1,
+                value: format!("value{}", i).into_bytes(),
+                expires_at: None,
+            };
+            (format!("key{:04}", i).into_bytes(), entry)
+        })
+        .collect();
+    let path = format!("{}/damaged.db", dir);
+    write_btree_to_binary_file(&memtable, &path, 10).unwrap();
+    assert!(verify(&path).unwrap().is_empty());
+
+    let table = Table::open(&path).unwrap();
+    let TableIndex::Blocks(blocks) = &table.index else {
+        panic!("no block index");
+    };
+    assert!(blocks.len() >= 4);
+    let damaged = [&blocks[1], &blocks[3]];
+
+    // One flipped byte in the middle of each
+    let mut bytes = fs::read(&path).unwrap();
+    for block in damaged {
+        bytes[(block.offset + block.size as u64 / 2) as usize] ^= 0xff;
+    }
+    fs::write(&path, bytes).unwrap();
+
+    let damage = verify(&path).unwrap();
+    let offsets: Vec<u64> = damage.iter().map(|d| d.offset).collect();
+    assert_eq!(offsets, [damaged[0].offset, damaged[1].offset]);
+    assert!(damage.iter().all(|d| d.len > 0));
+
+    // Reads of the damaged blocks fail, the others don't
+    let cache = BlockCache::new(1024 * 1024);
+    let key = damaged[0].last_key.clone();
+    assert!(matches!(
+        table.get(&key, &cache),
+        Err(DbError::Corruption { offset, .. }) if offset == damaged[0].offset
+    ));
+    let key = blocks[2].last_key.clone();
+    assert!(table.get(&key, &cache).is_ok());
+
+    // Across a data dir, only damaged tables are listed
+    write_btree_to_binary_file(&memtable, &format!("{}/intact.db", dir), 10).unwrap();
+    let report = verify_sstables(&dir).unwrap();
+    assert_eq!(report.len(), 1);
+    assert_eq!(report[0].0, "damaged.db");
+    assert_eq!(report[0].1.len(), 2);
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -1,60 +1,67 @@
-use std::{fs, sync::Arc};
+use std::sync::Arc;
 
-use tokio::time::{Duration, sleep};
-
-use crate::{storage_engine::engine::Engine, wal::Wal};
+use crate::{memtable::ImmutableMemtables, storage_engine::engine::Engine, wal::Wal};
 
+/// Background task that writes frozen memtables to SSTables as the `Db`
+/// hands them over, and runs compaction after each flush.
 pub struct Flusher<E: Engine + 'static + Send + Sync> {
-    wal: Arc<Wal<E>>,
+    wal: Arc<Wal>,
     storage_engine: Arc<E>,
-    flush_interval_secs: u64,
+    immutables: Arc<ImmutableMemtables>,
 }
 
 impl<E: Engine + Send + Sync + 'static> Flusher<E> {
-    pub fn new(flush_interval_secs: u64, wal: Arc<Wal<E>>, storage_engine: Arc<E>) -> Self {
+    pub fn new(wal: Arc<Wal>, storage_engine: Arc<E>, immutables: Arc<ImmutableMemtables>) -> Self {
         Flusher {
-            flush_interval_secs,
             wal,
             storage_engine,
+            immutables,
         }
     }
 
     pub fn start(&self) {
-        let interval = self.flush_interval_secs;
         let wal_clone = self.wal.clone();
         let storage_engine = self.storage_engine.clone();
-        let mut flush_count = 0;
+        let immutables = self.immutables.clone();
         println!("Flusher started");
         tokio::spawn(async move {
             loop {
-                let files = wal_clone.get_wal_files_available_for_snapshot().unwrap();
-
-                println!("available files found for flush {}", files.len());
-
-                match wal_clone.play_wal_to_store() {
-                    Ok(_) => {}
-                    Err(e) => println!("{:?}", e),
-                };
-
-                for file in files {
-                    match fs::remove_file(&file) {
-                        Ok(_) => (),
-                        Err(_) => println!("Failed to delete file {}", file),
-                    }
+                // Flushing and compaction are all disk I/O, so they run on
+                // the blocking pool rather than a runtime worker
+                let storage_engine = storage_engine.clone();
+                let wal = wal_clone.clone();
+                let queue = immutables.clone();
+                let drained = tokio::task::spawn_blocking(move || {
+                    flush_all(storage_engine.as_ref(), &wal, &queue)
+                })
+                .await;
+                if let Err(e) = drained {
+                    println!("Flusher task failed: {}", e);
                 }
 
-                flush_count += 1;
-                if flush_count >= 2 {
-                    // Run compaction every 2 flushes
-                    match storage_engine.compact_sstables() {
-                        Ok(_) => println!("SSTable compaction completed successfully"),
-                        Err(e) => println!("SSTable compaction failed: {:?}", e),
-                    }
-                    flush_count = 0; // Reset counter
-                }
-
-                sleep(Duration::from_secs(interval)).await;
+                immutables.wait_for_flush().await;
             }
         });
     }
 }
+
+/// Flushes every queued memtable, oldest first, compacting after each one.
+/// Tables frozen before startup finished are already queued.
+fn flush_all<E: Engine>(storage_engine: &E, wal: &Wal, immutables: &ImmutableMemtables) {
+    while !immutables.is_empty() {
+        match immutables.flush_oldest(storage_engine, wal) {
+            Ok(true) => println!("Memtable flushed up to seq {}", wal.last_checkpoint()),
+            Ok(false) => break,
+            Err(e) => {
+                println!("Memtable flush failed: {:?}", e);
+                break;
+            }
+        }
+
+        // Compaction only does work once a level is over its limit. It runs
+        // alongside flushes, so a writer flushing for itself doesn't wait on it.
+        if let Err(e) = storage_engine.compact_sstables() {
+            println!("SSTable compaction failed: {:?}", e);
+        }
+    }
+}
@@ -1,40 +1,80 @@
+pub mod bloom;
+pub mod cache;
 pub mod common;
+pub mod config;
 pub mod db;
 pub mod ende;
 pub mod flusher;
+pub mod manifest;
+pub mod memtable;
+pub mod protocol;
 pub mod storage_engine;
+pub mod sweeper;
 pub mod wal;
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt, BufReader},
     net::TcpListener,
 };
 
-
 use crate::{
-    common::command_type::CommandType,
+    config::{Config, DEFAULT_CONFIG_PATH},
     db::Db,
     flusher::Flusher,
-    storage_engine::{engine::Engine, sstable_engine::SSTableEngine},
+    memtable::ImmutableMemtables,
+    protocol::{http, resp, text},
+    storage_engine::sstable_engine::{SSTableEngine, verify_sstables},
+    sweeper::Sweeper,
     wal::Wal,
 };
 #[tokio::main]
 async fn main() {
+    // `mdb verify [dir]` checks the SSTables and exits
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("verify") {
+        let dir = args.next().unwrap_or_else(|| String::from("data"));
+        std::process::exit(verify(&dir));
+    }
+
     println!("Welcome to MiniDB (TCP Mode)");
 
-    // Shared storage engine
-    let storage_engine = Arc::new(SSTableEngine::new(String::from("data")));
-    let flusher_wal = Wal::new(String::from("wal"), SSTableEngine::new(String::from("data")));
+    let config_path =
+        std::env::var("MDB_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+    let config = Config::load(&config_path).expect("Failed to load config");
+    println!("WAL fsync policy: {:?}", config.wal.fsync);
+
+    // One WAL shared by the db (appends) and the flusher (checkpoints)
+    let wal = Arc::new(Wal::new(String::from("wal"), &config.wal).expect("Failed to open wal"));
+    wal.start_background_sync();
 
-    let flusher = Flusher::new(40, Arc::new(flusher_wal), storage_engine.clone());
+    // Frozen memtables handed from the db to the flusher
+    let immutables = Arc::new(ImmutableMemtables::new());
+
+    // Shared storage engine
+    let storage_engine = Arc::new(
+        SSTableEngine::with_config(String::from("data"), &config.sstable, &config.compaction)
+            .expect("Failed to open sstables"),
+    );
+
+    // Shared db between clients. Built before the flusher starts so WAL
+    // replay sees every segment before any of them is checkpointed away.
+    let db = Arc::new(
+        Db::new(
+            storage_engine.clone(),
+            wal.clone(),
+            immutables.clone(),
+            &config.memtable,
+        )
+        .expect("Failed to load db"),
+    );
+
+    let flusher = Flusher::new(wal.clone(), storage_engine.clone(), immutables);
     flusher.start();
 
-    // Shared db between clients
-    let sstable_engine = SSTableEngine::new(String::from("data"));
-    let wal = Wal::new(String::from("wal"), SSTableEngine::new(String::from("data")));
-    let db = Arc::new(tokio::sync::Mutex::new(
-        Db::new(sstable_engine, wal).expect("Failed to load db"),
-    ));
+    // Deletes expired keys from the memtable in the background
+    let sweep_interval = Duration::from_secs(config.expiry.sweep_interval_secs.max(1));
+    let sweeper = Sweeper::new(db.clone(), sweep_interval);
+    sweeper.start();
 
     // Listen on port 4000
     let listener = TcpListener::bind("0.0.0.0:4000")
@@ -43,6 +83,35 @@ async fn main() {
 
     println!("Listening on 0.0.0.0:4000 ...");
 
+    if let Some(addr) = &config.http.addr {
+        let http_listener = TcpListener::bind(addr)
+            .await
+            .unwrap_or_else(|e| panic!("Failed to bind HTTP address {}: {}", addr, e));
+        println!("HTTP listening on {} ...", addr);
+
+        let db = db.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, addr) = match http_listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        println!("HTTP accept failed: {}", e);
+                        continue;
+                    }
+                };
+
+                let db_clone = db.clone();
+                tokio::spawn(async move {
+                    let (reader, mut writer) = socket.into_split();
+                    let mut reader = BufReader::new(reader);
+                    if let Err(e) = http::serve(&mut reader, &mut writer, &db_clone).await {
+                        println!("HTTP client {} failed: {}", addr, e);
+                    }
+                });
+            }
+        });
+    }
+
     loop {
         let (socket, addr) = listener.accept().await.unwrap();
         println!("Client connected: {}", addr);
@@ -52,87 +121,50 @@ async fn main() {
         tokio::spawn(async move {
             let (reader, mut writer) = socket.into_split();
             let mut reader = BufReader::new(reader);
-            let mut line = String::new();
-
-            loop {
-                line.clear();
-                let bytes_read = reader.read_line(&mut line).await.unwrap();
-                if bytes_read == 0 {
-                    println!("Client {} disconnected", addr);
-                    break;
-                }
 
-                let input = line.trim();
-                if input.is_empty() {
-                    continue;
-                }
-
-                let parts: Vec<&str> = input.split_whitespace().collect();
-                if parts.is_empty() {
-                    writer.write_all(b"Invalid command\n").await.unwrap();
-                    continue;
+            // Redis clients send every command as a RESP array, which starts
+            // with `*`; line protocol requests start with a command name
+            if let Ok([b'*', ..]) = reader.fill_buf().await {
+                if let Err(e) = resp::serve(&mut reader, &mut writer, &db_clone).await {
+                    println!("Client {} failed: {}", addr, e);
                 }
+                println!("Client {} disconnected", addr);
+                return;
+            }
 
-                let command_type = match CommandType::command_type_from_str(parts[0]) {
-                    Some(c) => c,
-                    None => {
-                        writer.write_all(b"Invalid command\n").await.unwrap();
-                        continue;
-                    }
-                };
+            if let Err(e) = text::serve(&mut reader, &mut writer, &db_clone).await {
+                println!("Client {} failed: {}", addr, e);
+            }
+            println!("Client {} disconnected", addr);
+        });
+    }
+}
 
-                let mut db = db_clone.lock().await;
-
-                match command_type {
-                    CommandType::Set => match db.handle_set(&parts) {
-                        Ok(_) => {
-                            writer
-                                .write_all(format!("OK: inserted {}\n", parts[1]).as_bytes())
-                                .await
-                                .unwrap();
-                        }
-                        Err(e) => {
-                            writer
-                                .write_all(format!("ERR: {:?}\n", e).as_bytes())
-                                .await
-                                .unwrap();
-                        }
-                    },
-                    CommandType::Get => match db.handle_get(&parts) {
-                        Ok(val) => {
-                            writer
-                                .write_all(format!("{:?}\n", val).as_bytes())
-                                .await
-                                .unwrap();
-                        }
-                        Err(e) => {
-                            writer
-                                .write_all(format!("ERR: {:?}\n", e).as_bytes())
-                                .await
-                                .unwrap();
-                        }
-                    },
-                    CommandType::GetKeys => {
-                        writer
-                            .write_all(
-                                format!("{:?}\n", db.data.keys().collect::<Vec<_>>()).as_bytes(),
-                            )
-                            .await
-                            .unwrap();
-                    }
-                    CommandType::Delete => match db.handle_delete(&parts) {
-                        Ok(_) => {
-                            writer.write_all(b"OK: deleted\n").await.unwrap();
-                        }
-                        Err(e) => {
-                            writer
-                                .write_all(format!("ERR: {:?}\n", e).as_bytes())
-                                .await
-                                .unwrap();
-                        }
-                    },
+/// Report every damaged range in the SSTables under `dir`. Returns the exit code.
+fn verify(dir: &str) -> i32 {
+    match verify_sstables(dir) {
+        Ok(damaged) if damaged.is_empty() => {
+            println!("All SSTables in {} are intact", dir);
+            0
+        }
+        Ok(damaged) => {
+            for (filename, damage) in damaged {
+                for d in damage {
+                    println!(
+                        "{}/{}: bytes {}..{}: {}",
+                        dir,
+                        filename,
+                        d.offset,
+                        d.offset + d.len,
+                        d.reason
+                    );
                 }
             }
-        });
+            1
+        }
+        Err(e) => {
+            println!("Failed to verify {}: {:?}", dir, e);
+            2
+        }
     }
 }
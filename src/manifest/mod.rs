@@ -0,0 +1,308 @@
+use std::{
+    borrow::Borrow,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    common::{db_errors::DbError, fs::sync_dir},
+    wal::record::is_torn_tail,
+};
+
+pub const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Records larger than this are treated as corrupt rather than allocated.
+const MAX_EDIT_LEN: usize = 64 * 1024 * 1024;
+
+/// A live SSTable as recorded in the manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileMeta {
+    /// Unique, increasing file number. New files are named after it.
+    pub number: u64,
+    pub name: String,
+    /// 0 for memtable flushes, higher levels for compaction output.
+    pub level: u32,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub smallest_key: Vec<u8>,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub largest_key: Vec<u8>,
+    /// Highest WAL sequence number in the table. Within a level, tables with
+    /// a higher seq shadow older ones.
+    pub seq: u64,
+    pub size_bytes: u64,
+    /// Unix time (seconds) the newest data in the table was written, for
+    /// tombstone grace periods.
+    #[serde(default)]
+    pub created_at: u64,
+}
+
+impl FileMeta {
+    /// False if `key` is outside the table's key range.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.smallest_key.as_slice() <= key && key <= self.largest_key.as_slice()
+    }
+
+    /// True if the table's key range overlaps `smallest..=largest`.
+    pub fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        self.smallest_key.as_slice() <= largest && smallest <= self.largest_key.as_slice()
+    }
+}
+
+/// One atomic change to the set of live SSTables: a flush adds a file, a
+/// compaction adds its output and removes its inputs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct VersionEdit {
+    #[serde(default)]
+    pub added: Vec<FileMeta>,
+    /// File numbers of tables that are no longer live.
+    #[serde(default)]
+    pub removed: Vec<u64>,
+    /// Every file committed so far, including removed ones, is numbered
+    /// below this. Set by `apply`; 0 in manifests from before it was
+    /// recorded.
+    #[serde(default)]
+    pub next_file_number: u64,
+}
+
+/// Log of version edits in `{file_dir}/MANIFEST`, the only record of which
+/// SSTables are live. Each edit is framed like a WAL record:
+/// `[payload_len u32 BE][crc32 u32 BE][JSON payload]`, so a torn edit at the
+/// end is dropped on open and the flush or compaction it described never
+/// happened. A bad edit with more after it fails the open instead.
+pub struct Manifest {
+    log: Mutex<File>,
+    /// Live files in read order: by level, then newest first. A file keeps
+    /// its `Arc` across edits, so its count shows who still holds it.
+    files: RwLock<Vec<Arc<FileMeta>>>,
+    next_file_number: AtomicU64,
+    /// Recorded with every edit, see `VersionEdit::next_file_number`.
+    committed_file_number: AtomicU64,
+}
+
+impl Manifest {
+    pub fn exists(file_dir: &str) -> bool {
+        fs::exists(format!("{}/{}", file_dir, MANIFEST_FILE)).unwrap_or(false)
+    }
+
+    /// Replays the manifest in `file_dir` (empty if there is none) and
+    /// rewrites it as a single edit, so it doesn't grow without bound. Tables
+    /// the replayed edits removed are deleted first, since the rewritten
+    /// manifest no longer names them.
+    pub fn open(file_dir: &str) -> Result<Self, DbError> {
+        fs::create_dir_all(file_dir).map_err(|e| DbError::LoadFailed(e.to_string()))?;
+        let path = format!("{}/{}", file_dir, MANIFEST_FILE);
+
+        let mut files: Vec<FileMeta> = Vec::new();
+        let mut removed: Vec<String> = Vec::new();
+        let mut next_file_number = 0;
+        match fs::read(&path) {
+            Ok(buf) => {
+                let mut offset = 0;
+                while offset < buf.len() {
+                    let Some((edit, len)) = decode_edit(&buf[offset..]) else {
+                        if !is_torn_tail(&buf[offset..]) {
+                            return Err(DbError::LoadFailed(format!(
+                                "corrupt manifest edit in {} at offset {}",
+                                path, offset
+                            )));
+                        }
+                        println!(
+                            "Manifest edit at offset {} is torn, ignoring the rest",
+                            offset
+                        );
+                        break;
+                    };
+                    next_file_number = next_file_number.max(edit.next_file_number);
+                    removed.extend(
+                        files
+                            .iter()
+                            .filter(|f| edit.removed.contains(&f.number))
+                            .map(|f| f.name.clone()),
+                    );
+                    apply_edit(&mut files, edit);
+                    offset += len;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(DbError::LoadFailed(e.to_string())),
+        }
+
+        // A trivial move removes a file and adds it back. Anything else
+        // removed is left over from a compaction that crashed before
+        // deleting its inputs.
+        removed.retain(|name| !files.iter().any(|f| &f.name == name));
+        let mut deleted = false;
+        for name in &removed {
+            match fs::remove_file(format!("{}/{}", file_dir, name)) {
+                Ok(()) => deleted = true,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(DbError::LoadFailed(e.to_string())),
+            }
+        }
+        if deleted {
+            sync_dir(file_dir)?;
+        }
+
+        Manifest::write(file_dir, files, next_file_number)
+    }
+
+    /// Writes a manifest listing `files` as live, replacing any existing one.
+    /// It appears in one rename, so a data dir either has no manifest or one
+    /// with every file in it.
+    pub fn create(file_dir: &str, files: Vec<FileMeta>) -> Result<Self, DbError> {
+        Manifest::write(file_dir, files, 0)
+    }
+
+    fn write(
+        file_dir: &str,
+        mut files: Vec<FileMeta>,
+        next_file_number: u64,
+    ) -> Result<Self, DbError> {
+        fs::create_dir_all(file_dir).map_err(|e| DbError::LoadFailed(e.to_string()))?;
+        let path = format!("{}/{}", file_dir, MANIFEST_FILE);
+        sort_files(&mut files);
+        let next_file_number = files
+            .iter()
+            .map(|f| f.number + 1)
+            .chain([next_file_number, 1])
+            .max()
+            .unwrap_or(1);
+
+        // Snapshot of the live files replaces the log
+        let snapshot = VersionEdit {
+            added: files.clone(),
+            removed: Vec::new(),
+            next_file_number,
+        };
+        let tmp_path = format!("{}.tmp", path);
+        let mut tmp = File::create(&tmp_path).map_err(|e| DbError::SaveFailed(e.to_string()))?;
+        tmp.write_all(&encode_edit(&snapshot)?)
+            .map_err(|e| DbError::SaveFailed(e.to_string()))?;
+        tmp.sync_all()
+            .map_err(|e| DbError::SaveFailed(e.to_string()))?;
+        fs::rename(&tmp_path, &path).map_err(|e| DbError::SaveFailed(e.to_string()))?;
+        sync_dir(file_dir)?;
+
+        let log = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(|e| DbError::LoadFailed(e.to_string()))?;
+
+        Ok(Manifest {
+            log: Mutex::new(log),
+            files: RwLock::new(files.into_iter().map(Arc::new).collect()),
+            next_file_number: AtomicU64::new(next_file_number),
+            committed_file_number: AtomicU64::new(next_file_number),
+        })
+    }
+
+    /// Reserves a number for a new SSTable.
+    pub fn new_file_number(&self) -> u64 {
+        self.next_file_number.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// The number the next SSTable will get. On open, files numbered at or
+    /// above it were never committed.
+    pub fn next_file_number(&self) -> u64 {
+        self.next_file_number.load(Ordering::SeqCst)
+    }
+
+    /// Live files in read order: lower levels first, newest first within a
+    /// level. Holding the snapshot keeps its files from being deleted, see
+    /// `SSTableEngine::delete_obsolete`.
+    pub fn live_files(&self) -> Vec<Arc<FileMeta>> {
+        self.files.read().unwrap().clone()
+    }
+
+    /// Durably appends `edit` to the log, then makes it visible to readers.
+    /// This is the commit point of a flush or compaction: files it adds must
+    /// already be installed, and files it removes are deleted only after.
+    pub fn apply(&self, mut edit: VersionEdit) -> Result<(), DbError> {
+        let mut log = self.log.lock().unwrap();
+        edit.next_file_number = edit
+            .added
+            .iter()
+            .map(|f| f.number + 1)
+            .chain([self.committed_file_number.load(Ordering::SeqCst)])
+            .max()
+            .unwrap_or(0);
+        let buf = encode_edit(&edit)?;
+
+        log.write_all(&buf)
+            .map_err(|e| DbError::SaveFailed(e.to_string()))?;
+        log.sync_data()
+            .map_err(|e| DbError::SaveFailed(e.to_string()))?;
+
+        let mut files = self.live_files();
+        self.committed_file_number
+            .store(edit.next_file_number, Ordering::SeqCst);
+        apply_edit(&mut files, edit);
+        *self.files.write().unwrap() = files;
+
+        Ok(())
+    }
+}
+
+/// Keys are written as byte arrays. Manifests from before keys were binary
+/// hold them as strings, which are read as their UTF-8 bytes.
+fn deserialize_key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Key {
+        Bytes(Vec<u8>),
+        Text(String),
+    }
+
+    Ok(match Key::deserialize(deserializer)? {
+        Key::Bytes(bytes) => bytes,
+        Key::Text(text) => text.into_bytes(),
+    })
+}
+
+fn apply_edit<F: Borrow<FileMeta> + From<FileMeta>>(files: &mut Vec<F>, edit: VersionEdit) {
+    files.retain(|f| !edit.removed.contains(&f.borrow().number));
+    files.extend(edit.added.into_iter().map(F::from));
+    sort_files(files);
+}
+
+/// Read order: by level, then newest first.
+fn sort_files<F: Borrow<FileMeta>>(files: &mut [F]) {
+    files.sort_by(|a, b| {
+        let (a, b) = (a.borrow(), b.borrow());
+        a.level
+            .cmp(&b.level)
+            .then(b.seq.cmp(&a.seq))
+            .then(b.number.cmp(&a.number))
+    });
+}
+
+fn encode_edit(edit: &VersionEdit) -> Result<Vec<u8>, DbError> {
+    let payload = serde_json::to_vec(edit).map_err(|e| DbError::SaveFailed(e.to_string()))?;
+    let mut buf = Vec::with_capacity(8 + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+    buf.extend_from_slice(&payload);
+    Ok(buf)
+}
+
+/// Decodes the edit at the start of `buf` and returns it with its encoded length.
+fn decode_edit(buf: &[u8]) -> Option<(VersionEdit, usize)> {
+    let payload_len = u32::from_be_bytes(buf.get(0..4)?.try_into().ok()?) as usize;
+    if payload_len > MAX_EDIT_LEN {
+        return None;
+    }
+    let crc = u32::from_be_bytes(buf.get(4..8)?.try_into().ok()?);
+    let payload = buf.get(8..8 + payload_len)?;
+    if crc32fast::hash(payload) != crc {
+        return None;
+    }
+
+    let edit = serde_json::from_slice(payload).ok()?;
+    Some((edit, 8 + payload_len))
+}
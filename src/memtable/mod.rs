@@ -0,0 +1,116 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use tokio::sync::Notify;
+
+use crate::{
+    common::{db_errors::DbError, entry::Entry},
+    storage_engine::engine::Engine,
+    wal::Wal,
+};
+
+/// Size at which the memtable is frozen and flushed to an SSTable.
+pub const DEFAULT_MEMTABLE_SIZE_LIMIT_BYTES: usize = 4 * 1024 * 1024;
+
+/// Frozen memtables allowed to wait for the flusher before writers flush
+/// one themselves.
+pub const MAX_IMMUTABLE_MEMTABLES: usize = 2;
+
+/// In-memory table of the most recent writes, with the approximate number of
+/// bytes it holds and the WAL sequence number of the last write applied.
+#[derive(Default)]
+pub struct Memtable {
+    pub data: BTreeMap<Vec<u8>, Entry>,
+    pub size_bytes: usize,
+    pub last_seq: u64,
+}
+
+impl Memtable {
+    pub fn new() -> Self {
+        Memtable::default()
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, entry: Entry) {
+        let key_len = key.len();
+        let seq = entry.seq();
+        self.size_bytes += entry_size(&entry);
+        match self.data.insert(key, entry) {
+            Some(old) => self.size_bytes -= entry_size(&old),
+            None => self.size_bytes += key_len,
+        }
+        self.last_seq = self.last_seq.max(seq);
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Entry> {
+        self.data.get(key)
+    }
+}
+
+fn entry_size(entry: &Entry) -> usize {
+    entry.value().map_or(0, |v| v.len())
+}
+
+/// Memtables that were frozen once they filled up and are waiting to be
+/// written to SSTables. Shared by the `Db`, which reads from and freezes into
+/// it, and the `Flusher`, which drains it.
+#[derive(Default)]
+pub struct ImmutableMemtables {
+    /// Oldest first.
+    tables: RwLock<Vec<Arc<Memtable>>>,
+    /// Serializes flushes so each table is written exactly once, in order.
+    /// Compaction doesn't take it: it only reads tables the manifest has
+    /// committed, and its edit lands on top of any flush made meanwhile.
+    flush_lock: Mutex<()>,
+    flush_needed: Notify,
+}
+
+impl ImmutableMemtables {
+    pub fn new() -> Self {
+        ImmutableMemtables::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tables.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Snapshot of the frozen tables, newest first, for reads.
+    pub fn newest_first(&self) -> Vec<Arc<Memtable>> {
+        self.tables.read().unwrap().iter().rev().cloned().collect()
+    }
+
+    /// Queues a full memtable for flushing and wakes the flusher.
+    pub fn push(&self, memtable: Memtable) {
+        self.tables.write().unwrap().push(Arc::new(memtable));
+        self.flush_needed.notify_one();
+    }
+
+    pub async fn wait_for_flush(&self) {
+        self.flush_needed.notified().await;
+    }
+
+    /// Writes the oldest frozen table to an SSTable, checkpoints the WAL up to
+    /// its last write and drops it. Returns false if there was nothing to flush.
+    pub fn flush_oldest<E: Engine>(&self, engine: &E, wal: &Wal) -> Result<bool, DbError> {
+        let _guard = self.flush_lock.lock().unwrap();
+
+        let Some(oldest) = self.tables.read().unwrap().first().cloned() else {
+            return Ok(false);
+        };
+
+        if !oldest.data.is_empty() {
+            engine.save_all(&oldest.data, oldest.last_seq)?;
+        }
+        wal.checkpoint(oldest.last_seq)?;
+
+        // Readers now find these keys in the SSTable
+        self.tables.write().unwrap().remove(0);
+
+        Ok(true)
+    }
+}
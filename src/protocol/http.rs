@@ -0,0 +1,528 @@
+use std::{fmt, io, sync::Arc};
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{common::db_errors::DbError, db::Db, storage_engine::engine::Engine};
+
+/// Largest request body accepted, so a client can't make the server buffer
+/// an unbounded amount.
+pub const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Longest request line or header line accepted, including its line ending.
+pub const MAX_LINE_BYTES: usize = 16 * 1024;
+
+/// Most headers accepted in one request.
+pub const MAX_HEADERS: usize = 100;
+
+struct Request {
+    method: String,
+    /// Percent-decoded path, as bytes since keys can be any bytes.
+    path: Vec<u8>,
+    /// Percent-decoded query parameters, in order.
+    query: Vec<(String, Vec<u8>)>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, body: Value) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn no_content() -> Self {
+        Response {
+            status: 204,
+            content_type: "application/json",
+            body: Vec::new(),
+        }
+    }
+
+    /// `{"error": {"code": ..., "message": ...}}`, with the same codes as the
+    /// text protocol.
+    fn error(status: u16, code: &str, message: &str) -> Self {
+        Response::json(
+            status,
+            json!({ "error": { "code": code, "message": message } }),
+        )
+    }
+
+    fn from_db_error(e: &DbError) -> Self {
+        let status = match e {
+            DbError::KeyNotFound(_) | DbError::Deleted(_) => 404,
+            DbError::InvalidCommand(_) => 400,
+            _ => 500,
+        };
+        Response::error(status, e.code(), &e.to_string())
+    }
+}
+
+/// One operation of a `POST /batch` request.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchOp {
+    Get {
+        key: String,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    Put {
+        key: String,
+        value: String,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    Delete {
+        key: String,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+}
+
+/// How the keys and values of a batch op are written in JSON.
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+    /// As the string, so only UTF-8.
+    #[default]
+    Utf8,
+    /// Percent-encoded, so any bytes.
+    Percent,
+}
+
+impl Encoding {
+    fn decode(self, s: String) -> Vec<u8> {
+        match self {
+            Encoding::Utf8 => s.into_bytes(),
+            Encoding::Percent => percent_decode(&s, false),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Batch {
+    ops: Vec<BatchOp>,
+}
+
+/// Serves a connection speaking HTTP/1.1 until it disconnects or asks to
+/// close. The routes are:
+/// - `GET /kv/{key}`: the value as the raw body
+/// - `PUT /kv/{key}`: sets the key to the raw body
+/// - `DELETE /kv/{key}`: deletes the key
+/// - `GET /kv?prefix=&limit=`: `{"keys": [...]}`, in order and
+///   percent-encoded like in a `/kv/{key}` path
+/// - `POST /batch`: runs `{"ops": [{"op": "get" | "put" | "delete", ...}]}`
+///   in order and returns `{"results": [...]}`, one per op. An op with
+///   `"encoding": "percent"` has its key and value percent-encoded, and so
+///   does a get's result, as does any value that isn't UTF-8.
+///
+/// Errors are JSON, `{"error": {"code": ..., "message": ...}}`.
+pub async fn serve<E, R, W>(reader: &mut R, writer: &mut W, db: &Arc<Db<E>>) -> io::Result<()>
+where
+    E: Engine + Send + Sync + 'static,
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let request = match read_request(reader, writer).await {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // The stream can't be trusted past a malformed request
+                let status = if is_headers_too_large(&e) { 431 } else { 400 };
+                let response = Response::error(status, "BAD_REQUEST", &e.to_string());
+                return write_response(writer, &response, false).await;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let response = route(&request, db).await;
+        write_response(writer, &response, request.keep_alive).await?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+async fn route<E: Engine + Send + Sync + 'static>(request: &Request, db: &Arc<Db<E>>) -> Response {
+    let method = request.method.as_str();
+    if request.path == b"/kv" {
+        return match method {
+            "GET" => list(request, db).await,
+            _ => method_not_allowed(),
+        };
+    }
+    if request.path == b"/batch" {
+        return match method {
+            "POST" => batch(&request.body, db).await,
+            _ => method_not_allowed(),
+        };
+    }
+
+    let Some(key) = request.path.strip_prefix(b"/kv/") else {
+        return Response::error(404, "UNKNOWN_ROUTE", "No such route");
+    };
+    if key.is_empty() {
+        return Response::error(400, "INVALID_COMMAND", "The key is empty");
+    }
+    if !matches!(method, "GET" | "PUT" | "DELETE") {
+        return method_not_allowed();
+    }
+
+    let method = request.method.clone();
+    let key = key.to_vec();
+    let body = request.body.clone();
+    db.run_write(move |db| execute(&method, key, body, db))
+        .await
+        .unwrap_or_else(|e| Response::from_db_error(&e))
+}
+
+/// Runs a `/kv/{key}` request for `Db::run_write`.
+fn execute<E: Engine>(
+    method: &str,
+    key: Vec<u8>,
+    body: Vec<u8>,
+    db: &Db<E>,
+) -> (Response, Option<u64>) {
+    match method {
+        "GET" => match db.handle_get(&[b"GET".to_vec(), key]) {
+            Ok(value) => (
+                Response {
+                    status: 200,
+                    content_type: "application/octet-stream",
+                    body: value,
+                },
+                None,
+            ),
+            Err(e) => (Response::from_db_error(&e), None),
+        },
+        "PUT" => match db.handle_set(&[b"SET".to_vec(), key, body]) {
+            Ok(seq) => (Response::no_content(), Some(seq)),
+            Err(e) => (Response::from_db_error(&e), None),
+        },
+        "DELETE" => match db.handle_delete(&[b"DELETE".to_vec(), key]) {
+            Ok(seq) => (Response::no_content(), Some(seq)),
+            Err(e) => (Response::from_db_error(&e), None),
+        },
+        _ => (method_not_allowed(), None),
+    }
+}
+
+async fn list<E: Engine + Send + Sync + 'static>(request: &Request, db: &Arc<Db<E>>) -> Response {
+    let mut prefix = Vec::new();
+    let mut limit = usize::MAX;
+    for (name, value) in &request.query {
+        match name.as_str() {
+            "prefix" => prefix = value.clone(),
+            "limit" => match std::str::from_utf8(value).ok().and_then(|v| v.parse().ok()) {
+                Some(value) => limit = value,
+                None => {
+                    return Response::error(
+                        400,
+                        "INVALID_COMMAND",
+                        "limit must be a non-negative integer",
+                    );
+                }
+            },
+            _ => {}
+        }
+    }
+
+    let keys = match db.run_blocking(move |db| db.keys(&prefix, limit)).await {
+        Ok(keys) => keys,
+        Err(e) => return Response::from_db_error(&e),
+    };
+    let keys: Vec<String> = keys.iter().map(|key| percent_encode(key)).collect();
+    Response::json(200, json!({ "keys": keys }))
+}
+
+/// Runs the ops in order, then waits for a single fsync covering all of them.
+/// Other clients' writes may land between ops.
+async fn batch<E: Engine + Send + Sync + 'static>(body: &[u8], db: &Arc<Db<E>>) -> Response {
+    let batch: Batch = match serde_json::from_slice(body) {
+        Ok(batch) => batch,
+        Err(e) => {
+            return Response::error(400, "INVALID_COMMAND", &format!("Invalid batch: {}", e));
+        }
+    };
+
+    match db.run_write(move |db| run_batch(batch.ops, db)).await {
+        Ok(results) => Response::json(200, json!({ "results": results })),
+        Err(e) => Response::from_db_error(&e),
+    }
+}
+
+/// Runs batch ops against the db and returns their results with the WAL
+/// sequence number of the last write.
+fn run_batch<E: Engine>(ops: Vec<BatchOp>, db: &Db<E>) -> (Vec<Value>, Option<u64>) {
+    let mut results = Vec::with_capacity(ops.len());
+    let mut last_seq = None;
+    for op in ops {
+        let result = match op {
+            BatchOp::Get { key, encoding } => db
+                .handle_get(&[b"GET".to_vec(), encoding.decode(key)])
+                .map(|value| match String::from_utf8(value) {
+                    Ok(value) if encoding == Encoding::Utf8 => json!({ "value": value }),
+                    value => {
+                        let value = value.map_or_else(|e| e.into_bytes(), String::into_bytes);
+                        json!({ "value": percent_encode(&value), "encoding": "percent" })
+                    }
+                }),
+            BatchOp::Put {
+                key,
+                value,
+                encoding,
+            } => db
+                .handle_set(&[
+                    b"SET".to_vec(),
+                    encoding.decode(key),
+                    encoding.decode(value),
+                ])
+                .map(|seq| {
+                    last_seq = Some(seq);
+                    json!({ "ok": true })
+                }),
+            BatchOp::Delete { key, encoding } => db
+                .handle_delete(&[b"DELETE".to_vec(), encoding.decode(key)])
+                .map(|seq| {
+                    last_seq = Some(seq);
+                    json!({ "ok": true })
+                }),
+        };
+        results.push(result.unwrap_or_else(
+            |e| json!({ "error": { "code": e.code(), "message": e.to_string() } }),
+        ));
+    }
+    (results, last_seq)
+}
+
+fn method_not_allowed() -> Response {
+    Response::error(405, "METHOD_NOT_ALLOWED", "Method not allowed")
+}
+
+/// Reads one request, or returns `None` once the client has disconnected.
+/// A malformed request is an `InvalidData` error, as is one whose request
+/// line or headers go past `MAX_LINE_BYTES` or `MAX_HEADERS`.
+async fn read_request<R, W>(reader: &mut R, writer: &mut W) -> io::Result<Option<Request>>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut line = String::new();
+    // Blank lines between requests are allowed
+    while line.trim().is_empty() {
+        line.clear();
+        if !read_line(reader, &mut line).await? {
+            return Ok(None);
+        }
+        if !line.ends_with('\n') {
+            return Err(bad_request("request line is too long"));
+        }
+    }
+
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(bad_request("malformed request line"));
+    };
+    let method = method.to_string();
+    let target = target.to_string();
+    let http_1_0 = version == "HTTP/1.0";
+    let mut keep_alive = !http_1_0;
+
+    let mut content_length = 0;
+    let mut expect_continue = false;
+    let mut headers = 0;
+    loop {
+        line.clear();
+        if !read_line(reader, &mut line).await? {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "client disconnected in the middle of the headers",
+            ));
+        }
+        if !line.ends_with('\n') {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, HeadersTooLarge));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, HeadersTooLarge));
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(bad_request("malformed header"));
+        };
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .map_err(|_| bad_request("invalid Content-Length"))?;
+            }
+            "transfer-encoding" => {
+                return Err(bad_request(
+                    "chunked bodies are not supported, send a Content-Length",
+                ));
+            }
+            "connection" if value.eq_ignore_ascii_case("close") => keep_alive = false,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => keep_alive = true,
+            "expect" if value.eq_ignore_ascii_case("100-continue") => expect_continue = true,
+            _ => {}
+        }
+    }
+    if content_length > MAX_BODY_BYTES {
+        return Err(bad_request("request body is too large"));
+    }
+
+    // curl waits for this before sending larger bodies
+    if expect_continue && !http_1_0 {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                String::from_utf8_lossy(&percent_decode(name, true)).into_owned(),
+                percent_decode(value, true),
+            )
+        })
+        .collect();
+
+    Ok(Some(Request {
+        method,
+        path: percent_decode(path, false),
+        query,
+        body,
+        keep_alive,
+    }))
+}
+
+/// Appends the next line to `line`, reading at most `MAX_LINE_BYTES`, so
+/// a line without a newline at the end was cut off there. Returns false at
+/// the end of the stream.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R, line: &mut String) -> io::Result<bool> {
+    let read = reader.take(MAX_LINE_BYTES as u64).read_line(line).await?;
+    Ok(read > 0)
+}
+
+/// The client sent a header line longer than `MAX_LINE_BYTES` or more than
+/// `MAX_HEADERS` headers.
+#[derive(Debug)]
+struct HeadersTooLarge;
+
+impl fmt::Display for HeadersTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "headers are limited to {} lines of {} bytes",
+            MAX_HEADERS, MAX_LINE_BYTES
+        )
+    }
+}
+
+impl std::error::Error for HeadersTooLarge {}
+
+/// Whether `e`, returned by `read_request`, is answered with 431 rather
+/// than 400.
+fn is_headers_too_large(e: &io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.is::<HeadersTooLarge>())
+}
+
+/// Decodes `%XX` escapes, and in a query string `+` as a space. Anything
+/// that isn't a valid escape is kept as it is.
+fn percent_decode(s: &str, query: bool) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) if query => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// Escapes every byte but unreserved URL characters, `:`, `@` and `/` as
+/// `%XX`, so `percent_decode` gives back the same bytes.
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        if byte.is_ascii_alphanumeric() || b"-._~:@/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn bad_request(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    response: &Response,
+    keep_alive: bool,
+) -> io::Result<()> {
+    let mut buf = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len(),
+        if keep_alive { "keep-alive" } else { "close" },
+    )
+    .into_bytes();
+    buf.extend_from_slice(&response.body);
+    writer.write_all(&buf).await
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    }
+}
@@ -0,0 +1,195 @@
+pub mod http;
+pub mod resp;
+pub mod text;
+
+#[cfg(test)]
+mod tests;
+
+use std::{fmt, io};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+use crate::wal::MAX_KEY_VALUE_LEN;
+
+/// Longest `$<len>` literal accepted. Anything longer couldn't be logged anyway.
+const MAX_LITERAL_LEN: u64 = MAX_KEY_VALUE_LEN as u64;
+
+/// Reads one request from a client and returns its arguments, or `None` once
+/// the client has disconnected.
+///
+/// A request is a line of arguments separated by whitespace. Version 1
+/// stops there, so a value like `$100` is just that.
+///
+/// With `v2` (protocol version 2), an argument written as `$<len>` followed
+/// by one space (or newline) is a literal: the next `len` bytes are the
+/// argument as they are, spaces, newlines and non-UTF-8 bytes included. So
+/// `SET $6 my key $11 hello world` sets the key `my key`. After a literal the
+/// request continues as before. A literal over `MAX_LITERAL_LEN` bytes is an
+/// `InvalidData` error after which the rest of the stream can't be parsed,
+/// see `is_fatal`. Arguments may also be quoted: `"..."` with the escapes
+/// `\n \r \t \0 \\ \" \xHH`, or `'...'` taken as is except for `\'`.
+///
+/// A request that doesn't parse is an `InvalidData` error; the whole line has
+/// been consumed, so the next request can follow.
+pub async fn read_request<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    v2: bool,
+) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+
+    let mut args = Vec::new();
+    let mut pos = 0;
+    loop {
+        while pos < line.len() && line[pos] != b'\n' && line[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if pos >= line.len() || line[pos] == b'\n' {
+            break;
+        }
+
+        if v2 && matches!(line[pos], b'"' | b'\'') {
+            let (arg, end) = parse_quoted(&line, pos)?;
+            args.push(arg);
+            pos = end;
+            continue;
+        }
+
+        let start = pos;
+        while pos < line.len() && !line[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        let Some(len) = literal_len(&line[start..pos]).filter(|_| v2) else {
+            args.push(line[start..pos].to_vec());
+            continue;
+        };
+
+        // Skip the separator, then read on until the whole literal is buffered
+        pos += if line[pos..].starts_with(b"\r\n") {
+            2
+        } else {
+            1
+        };
+        if len > MAX_LITERAL_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, LiteralTooLong));
+        }
+        let len = len as usize;
+        while line.len() < pos + len {
+            if reader.read_until(b'\n', &mut line).await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "client disconnected in the middle of a literal",
+                ));
+            }
+        }
+        args.push(line[pos..pos + len].to_vec());
+        pos += len;
+    }
+
+    Ok(Some(args))
+}
+
+/// Parses the quoted argument starting at `line[start]` and returns it with
+/// the position after the closing quote.
+fn parse_quoted(line: &[u8], start: usize) -> io::Result<(Vec<u8>, usize)> {
+    let quote = line[start];
+    let mut arg = Vec::new();
+    let mut pos = start + 1;
+    loop {
+        let c = *line
+            .get(pos)
+            .ok_or_else(|| invalid_request("unbalanced quotes"))?;
+        match c {
+            b'\n' => return Err(invalid_request("unbalanced quotes")),
+            c if c == quote => {
+                pos += 1;
+                break;
+            }
+            b'\\' if quote == b'\'' => {
+                if line.get(pos + 1) == Some(&b'\'') {
+                    arg.push(b'\'');
+                    pos += 2;
+                } else {
+                    arg.push(b'\\');
+                    pos += 1;
+                }
+            }
+            b'\\' => {
+                let escaped = *line
+                    .get(pos + 1)
+                    .ok_or_else(|| invalid_request("unbalanced quotes"))?;
+                pos += 2;
+                arg.push(match escaped {
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    b'0' => 0,
+                    b'x' => {
+                        let hex = line
+                            .get(pos..pos + 2)
+                            .and_then(|hex| std::str::from_utf8(hex).ok())
+                            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                            .ok_or_else(|| invalid_request("invalid \\x escape"))?;
+                        pos += 2;
+                        hex
+                    }
+                    other => other,
+                });
+            }
+            c => {
+                arg.push(c);
+                pos += 1;
+            }
+        }
+    }
+
+    // `"a"b` is more likely a mistake than one argument
+    if line.get(pos).is_some_and(|c| !c.is_ascii_whitespace()) {
+        return Err(invalid_request("closing quote must be followed by a space"));
+    }
+    Ok((arg, pos))
+}
+
+/// The client sent a literal longer than `MAX_LITERAL_LEN`. Its bytes can't
+/// be told apart from requests, so the connection has to be closed.
+#[derive(Debug)]
+struct LiteralTooLong;
+
+impl fmt::Display for LiteralTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "literal is longer than {} bytes", MAX_LITERAL_LEN)
+    }
+}
+
+impl std::error::Error for LiteralTooLong {}
+
+/// Whether `e`, returned by `read_request`, leaves the stream out of step, so
+/// the connection should be closed once the error is reported.
+pub fn is_fatal(e: &io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.is::<LiteralTooLong>())
+}
+
+fn invalid_request(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Length of a `$<len>` literal marker, or `None` for any other argument.
+/// Lengths too big for a u64 come back as `u64::MAX`.
+fn literal_len(token: &[u8]) -> Option<u64> {
+    let digits = token.strip_prefix(b"$")?;
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    Some(digits.iter().fold(0u64, |len, digit| {
+        len.saturating_mul(10).saturating_add((digit - b'0') as u64)
+    }))
+}
+
+/// Appends `value` as a `$<len> <bytes>` literal, the same framing requests use.
+pub fn write_literal(buf: &mut Vec<u8>, value: &[u8]) {
+    buf.extend_from_slice(format!("${} ", value.len()).as_bytes());
+    buf.extend_from_slice(value);
+}
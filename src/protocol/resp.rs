@@ -0,0 +1,421 @@
+use std::{io, sync::Arc};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    common::db_errors::DbError, db::Db, storage_engine::engine::Engine, wal::MAX_KEY_VALUE_LEN,
+};
+
+/// Bulk strings larger than this are rejected rather than allocated. Nothing
+/// larger could be logged to the WAL anyway.
+const MAX_BULK_LEN: usize = MAX_KEY_VALUE_LEN;
+/// Commands with more arguments than this are rejected.
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// A RESP reply. Connections start on RESP2 and switch to RESP3 with
+/// `HELLO 3`, which changes how nil and maps are sent.
+pub enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Reply>),
+    /// Sent as a flat array of keys and values on RESP2.
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    pub fn encode(&self, buf: &mut Vec<u8>, resp3: bool) {
+        match self {
+            Reply::Simple(s) => buf.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Error(e) => buf.extend_from_slice(format!("-{}\r\n", e).as_bytes()),
+            Reply::Integer(n) => buf.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(b) => {
+                buf.extend_from_slice(format!("${}\r\n", b.len()).as_bytes());
+                buf.extend_from_slice(b);
+                buf.extend_from_slice(b"\r\n");
+            }
+            Reply::Nil if resp3 => buf.extend_from_slice(b"_\r\n"),
+            Reply::Nil => buf.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(items) => {
+                buf.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(buf, resp3);
+                }
+            }
+            Reply::Map(pairs) => {
+                let header = if resp3 {
+                    format!("%{}\r\n", pairs.len())
+                } else {
+                    format!("*{}\r\n", pairs.len() * 2)
+                };
+                buf.extend_from_slice(header.as_bytes());
+                for (key, value) in pairs {
+                    key.encode(buf, resp3);
+                    value.encode(buf, resp3);
+                }
+            }
+        }
+    }
+}
+
+/// Serves a connection whose client speaks RESP, as Redis clients do, until
+/// it disconnects or sends something that isn't RESP.
+pub async fn serve<E, R, W>(reader: &mut R, writer: &mut W, db: &Arc<Db<E>>) -> io::Result<()>
+where
+    E: Engine + Send + Sync + 'static,
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut resp3 = false;
+    let mut buf = Vec::new();
+    loop {
+        let args = match read_command(reader).await {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                let reply = Reply::Error(format!("ERR Protocol error: {}", e));
+                buf.clear();
+                reply.encode(&mut buf, resp3);
+                return writer.write_all(&buf).await;
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        let reply = match name.as_str() {
+            "HELLO" => hello(&args, &mut resp3),
+            "QUIT" => Reply::Simple("OK"),
+            _ => {
+                let name = name.clone();
+                db.run_write(move |db| execute(&name, &args, db))
+                    .await
+                    .unwrap_or_else(error_reply)
+            }
+        };
+
+        buf.clear();
+        reply.encode(&mut buf, resp3);
+        writer.write_all(&buf).await?;
+        if name == "QUIT" {
+            return Ok(());
+        }
+    }
+}
+
+/// Runs a RESP command for `Db::run_write`; argument counts are checked here.
+fn execute<E: Engine>(name: &str, args: &[Vec<u8>], db: &Db<E>) -> (Reply, Option<u64>) {
+    let arity_ok = match name {
+        "PING" => args.len() <= 2,
+        "ECHO" | "GET" | "KEYS" | "TTL" | "PERSIST" | "INCR" | "DECR" => args.len() == 2,
+        "SET" => args.len() >= 3,
+        "CAS" => args.len() == 4,
+        "EXPIRE" | "INCRBY" | "INCRBYFLOAT" => args.len() == 3,
+        "DEL" | "EXISTS" => args.len() >= 2,
+        _ => true,
+    };
+    if !arity_ok {
+        return (
+            Reply::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            )),
+            None,
+        );
+    }
+
+    match name {
+        "PING" => match args.get(1) {
+            Some(message) => (Reply::Bulk(message.clone()), None),
+            None => (Reply::Simple("PONG"), None),
+        },
+        "ECHO" => (Reply::Bulk(args[1].clone()), None),
+        "GET" => match db.handle_get(args) {
+            Ok(value) => (Reply::Bulk(value), None),
+            Err(DbError::KeyNotFound(_) | DbError::Deleted(_)) => (Reply::Nil, None),
+            Err(e) => (error_reply(e), None),
+        },
+        "SET" => match db.handle_set(args) {
+            Ok(seq) => (Reply::Simple("OK"), Some(seq)),
+            // NX or XX didn't hold
+            Err(DbError::ConditionFailed(_)) => (Reply::Nil, None),
+            Err(e) => (error_reply(e), None),
+        },
+        "CAS" => match db.handle_cas(args) {
+            Ok(seq) => (Reply::Integer(1), Some(seq)),
+            Err(DbError::ConditionFailed(_)) => (Reply::Integer(0), None),
+            Err(e) => (error_reply(e), None),
+        },
+        "EXPIRE" => match db.handle_expire(args) {
+            Ok(Some(seq)) => (Reply::Integer(1), Some(seq)),
+            Ok(None) => (Reply::Integer(0), None),
+            Err(e) => (error_reply(e), None),
+        },
+        "TTL" => match db.handle_ttl(args) {
+            Ok(ttl) => (Reply::Integer(ttl), None),
+            Err(e) => (error_reply(e), None),
+        },
+        "PERSIST" => match db.handle_persist(args) {
+            Ok(Some(seq)) => (Reply::Integer(1), Some(seq)),
+            Ok(None) => (Reply::Integer(0), None),
+            Err(e) => (error_reply(e), None),
+        },
+        "INCR" | "DECR" | "INCRBY" => {
+            let result = match name {
+                "INCR" => db.handle_incr(args),
+                "DECR" => db.handle_decr(args),
+                _ => db.handle_incr_by(args),
+            };
+            match result {
+                Ok((value, seq)) => (Reply::Integer(value), Some(seq)),
+                Err(e) => (error_reply(e), None),
+            }
+        }
+        "INCRBYFLOAT" => match db.handle_incr_by_float(args) {
+            Ok((value, seq)) => (Reply::Bulk(value), Some(seq)),
+            Err(e) => (error_reply(e), None),
+        },
+        "DEL" => match db.delete_existing(&args[1..]) {
+            Ok((deleted, last_seq)) => (Reply::Integer(deleted as i64), last_seq),
+            Err(e) => (error_reply(e), None),
+        },
+        "EXISTS" => {
+            let mut found = 0;
+            for key in &args[1..] {
+                match exists(db, key) {
+                    Ok(true) => found += 1,
+                    Ok(false) => {}
+                    Err(e) => return (error_reply(e), None),
+                }
+            }
+            (Reply::Integer(found), None)
+        }
+        "KEYS" => {
+            let pattern = &args[1];
+            match db.keys(glob_prefix(pattern), usize::MAX) {
+                Ok(keys) => {
+                    let keys = keys
+                        .into_iter()
+                        .filter(|key| glob_match(pattern, key))
+                        .map(Reply::Bulk)
+                        .collect();
+                    (Reply::Array(keys), None)
+                }
+                Err(e) => (error_reply(e), None),
+            }
+        }
+        // redis-cli asks for command docs on startup; it does fine without them
+        "COMMAND" => (Reply::Array(Vec::new()), None),
+        _ => (
+            Reply::Error(format!(
+                "ERR unknown command '{}'",
+                String::from_utf8_lossy(&args[0])
+            )),
+            None,
+        ),
+    }
+}
+
+/// `HELLO [protover]`: switches the connection to RESP2 or RESP3 and
+/// describes the server.
+fn hello(args: &[Vec<u8>], resp3: &mut bool) -> Reply {
+    if let Some(version) = args.get(1) {
+        match version.as_slice() {
+            b"2" => *resp3 = false,
+            b"3" => *resp3 = true,
+            _ => {
+                return Reply::Error("NOPROTO unsupported protocol version".to_string());
+            }
+        }
+    }
+
+    let field = |name: &str| Reply::Bulk(name.as_bytes().to_vec());
+    Reply::Map(vec![
+        (field("server"), field("mdb")),
+        (field("version"), field(env!("CARGO_PKG_VERSION"))),
+        (field("proto"), Reply::Integer(if *resp3 { 3 } else { 2 })),
+        (field("mode"), field("standalone")),
+        (field("role"), field("master")),
+        (field("modules"), Reply::Array(Vec::new())),
+    ])
+}
+
+fn exists<E: Engine>(db: &Db<E>, key: &[u8]) -> Result<bool, DbError> {
+    match db.handle_get(&[b"GET".to_vec(), key.to_vec()]) {
+        Ok(_) => Ok(true),
+        Err(DbError::KeyNotFound(_) | DbError::Deleted(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn error_reply(e: DbError) -> Reply {
+    let message = e.to_string().replace(['\r', '\n'], " ");
+    Reply::Error(format!("ERR {} {}", e.code(), message))
+}
+
+/// Reads one command, sent as a RESP array of bulk strings, or `None` once
+/// the client has disconnected. Malformed input is an `InvalidData` error.
+pub async fn read_command<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader).await? else {
+        return Ok(None);
+    };
+    let count = parse_header(&line, b'*', MAX_ARGS)?;
+
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let line = read_line(reader)
+            .await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let len = parse_header(&line, b'$', MAX_BULK_LEN)?;
+
+        let mut arg = vec![0u8; len + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid_data("expected CRLF after bulk string"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Reads a line and strips its CRLF.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\r\n") {
+        return Err(invalid_data("expected CRLF"));
+    }
+    line.truncate(line.len() - 2);
+    Ok(Some(line))
+}
+
+/// Parses a `*<count>` or `$<len>` header line.
+fn parse_header(line: &[u8], kind: u8, max: usize) -> io::Result<usize> {
+    let digits = line
+        .strip_prefix(&[kind])
+        .ok_or_else(|| invalid_data(&format!("expected '{}'", kind as char)))?;
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|n| *n <= max)
+        .ok_or_else(|| invalid_data("invalid length"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// The literal start of a glob pattern, which every matching key begins with.
+fn glob_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|c| matches!(c, b'*' | b'?' | b'[' | b'\\'))
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+/// Redis-style glob match: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and how much text it has swallowed so far
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    star = Some((p, t));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, len)) = match_class(&pattern[p..], text[t]) {
+                        if matched {
+                            p += len;
+                            t += 1;
+                            continue;
+                        }
+                    } else if text[t] == b'[' {
+                        // An unterminated class is a literal `[`
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() && pattern[p + 1] == text[t] => {
+                    p += 2;
+                    t += 1;
+                    continue;
+                }
+                b'\\' if p + 1 < pattern.len() => {}
+                c if c == text[t] => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        // Mismatch: let the last `*` take one more byte and retry
+        let Some((star_p, star_t)) = star else {
+            return false;
+        };
+        star = Some((star_p, star_t + 1));
+        p = star_p + 1;
+        t = star_t + 1;
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Matches `c` against the `[...]` class at the start of `pattern`, returning
+/// whether it matched and the length of the class, or `None` if the class
+/// isn't closed.
+fn match_class(pattern: &[u8], c: u8) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = pattern.get(i) == Some(&b'^');
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    loop {
+        let mut start = *pattern.get(i)?;
+        if start == b']' {
+            return Some((matched != negated, i + 1));
+        }
+        if start == b'\\' {
+            i += 1;
+            start = *pattern.get(i)?;
+        }
+
+        if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2).is_some_and(|e| *e != b']') {
+            let end = pattern[i + 2];
+            let (low, high) = if start <= end {
+                (start, end)
+            } else {
+                (end, start)
+            };
+            matched |= (low..=high).contains(&c);
+            i += 3;
+        } else {
+            matched |= start == c;
+            i += 1;
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use std::{io, sync::Arc};
+
+use crate::{
+    db::{
+        Db,
+        tests::{open, temp_dir},
+    },
+    protocol::{http, is_fatal, read_request, resp},
+    storage_engine::sstable_engine::SSTableEngine,
+};
+
+/// Every request in `input`, until the first error or the end.
+async fn requests(mut input: &[u8], v2: bool) -> (Vec<Vec<String>>, Option<io::Error>) {
+    let mut requests = Vec::new();
+    loop {
+        match read_request(&mut input, v2).await {
+            Ok(Some(args)) => requests.push(
+                args.iter()
+                    .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                    .collect(),
+            ),
+            Ok(None) => return (requests, None),
+            Err(e) => return (requests, Some(e)),
+        }
+    }
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[tokio::test]
+async fn arguments_are_split_on_whitespace() {
+    let (parsed, err) = requests(b"SET  key\tvalue\nGET key\r\n\n", false).await;
+    assert!(err.is_none());
+    assert_eq!(
+        parsed,
+        [
+            args(&["SET", "key", "value"]),
+            args(&["GET", "key"]),
+            args(&[])
+        ]
+    );
+}
+
+#[tokio::test]
+async fn literals_keep_spaces_and_newlines() {
+    let input = b"SET $6 my key $11 hello\nworld\nGET $6 my key\n";
+    let (parsed, err) = requests(input, true).await;
+    assert!(err.is_none());
+    assert_eq!(
+        parsed,
+        [
+            args(&["SET", "my key", "hello\nworld"]),
+            args(&["GET", "my key"])
+        ]
+    );
+
+    // A marker at the end of a line is followed by its literal on the next
+    let (parsed, _) = requests(b"SET k $5\nhello\nGET k\n", true).await;
+    assert_eq!(parsed, [args(&["SET", "k", "hello"]), args(&["GET", "k"])]);
+}
+
+#[tokio::test]
+async fn version_1_has_no_literals() {
+    // A legacy client's `$100` is a value, not the start of 100 raw bytes
+    let (parsed, err) = requests(b"SET price $100\nGET price\n", false).await;
+    assert!(err.is_none());
+    assert_eq!(
+        parsed,
+        [args(&["SET", "price", "$100"]), args(&["GET", "price"])]
+    );
+}
+
+#[tokio::test]
+async fn crlf_after_a_literal_marker_is_one_separator() {
+    let (parsed, err) = requests(b"SET k $5\r\nhello\r\nGET k\r\n", true).await;
+    assert!(err.is_none());
+    assert_eq!(parsed, [args(&["SET", "k", "hello"]), args(&["GET", "k"])]);
+}
+
+#[tokio::test]
+async fn truncated_literal_is_an_eof_error() {
+    let (parsed, err) = requests(b"SET k $10 short\n", true).await;
+    assert!(parsed.is_empty());
+    assert_eq!(err.unwrap().kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[tokio::test]
+async fn oversized_literal_is_rejected() {
+    for input in [
+        &b"GET $18446744073709551615 abc\n"[..],
+        b"GET $99999999999999999999999 abc\n",
+        b"SET k $67108864 abc\n",
+    ] {
+        let (parsed, err) = requests(input, true).await;
+        assert!(parsed.is_empty());
+        let err = err.unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(is_fatal(&err));
+    }
+}
+
+#[tokio::test]
+async fn quoted_arguments_need_quoting() {
+    let input = b"SET \"a b\" 'it\\'s' \"\\x41\\n\\t\\\\\"\n";
+    let (parsed, err) = requests(input, true).await;
+    assert!(err.is_none());
+    assert_eq!(parsed, [args(&["SET", "a b", "it's", "A\n\t\\"])]);
+
+    // Version 1 takes quotes as part of the argument
+    let (parsed, _) = requests(b"SET \"a b\"\n", false).await;
+    assert_eq!(parsed, [args(&["SET", "\"a", "b\""])]);
+}
+
+#[tokio::test]
+async fn bad_quoting_consumes_only_its_line() {
+    for input in [
+        &b"SET \"open\nGET k\n"[..],
+        b"SET \"a\"b\nGET k\n",
+        b"SET \"\\xZZ\"\nGET k\n",
+    ] {
+        let mut reader = input;
+        let err = read_request(&mut reader, true).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!is_fatal(&err));
+        let next = read_request(&mut reader, true).await.unwrap().unwrap();
+        assert_eq!(next, [b"GET".to_vec(), b"k".to_vec()]);
+    }
+}
+
+/// Runs `commands` over one RESP connection and returns everything the
+/// server wrote back.
+async fn resp_session(db: &Arc<Db<SSTableEngine>>, commands: &[&[&str]]) -> String {
+    let mut input = Vec::new();
+    for command in commands {
+        input.extend_from_slice(format!("*{}\r\n", command.len()).as_bytes());
+        for arg in *command {
+            input.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+    }
+    let mut output = Vec::new();
+    resp::serve(&mut input.as_slice(), &mut output, db)
+        .await
+        .unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+#[tokio::test]
+async fn resp_set_get_and_exists() {
+    let db = Arc::new(open(&temp_dir("resp-set-get")));
+    let output = resp_session(
+        &db,
+        &[
+            &["SET", "k", "hello world"],
+            &["GET", "k"],
+            &["GET", "missing"],
+            &["EXISTS", "k", "missing", "k"],
+            &["SET", "k", "v", "NX"],
+            &["SET", "k"],
+        ],
+    )
+    .await;
+    assert_eq!(
+        output,
+        "+OK\r\n$11\r\nhello world\r\n$-1\r\n:2\r\n$-1\r\n\
+         -ERR wrong number of arguments for 'set' command\r\n"
+    );
+}
+
+#[tokio::test]
+async fn resp_del_counts_only_existing_keys() {
+    let db = Arc::new(open(&temp_dir("resp-del")));
+    let output = resp_session(
+        &db,
+        &[
+            &["SET", "a", "1"],
+            &["SET", "b", "2"],
+            &["DEL", "a", "missing", "b", "a"],
+            &["DEL", "a"],
+            &["EXISTS", "a", "b"],
+        ],
+    )
+    .await;
+    assert_eq!(output, "+OK\r\n+OK\r\n:2\r\n:0\r\n:0\r\n");
+
+    // Missing keys leave nothing behind, not even a tombstone
+    assert!(db.data.read().unwrap().get(b"missing").is_none());
+}
+
+#[tokio::test]
+async fn resp_keys_matches_globs() {
+    let db = Arc::new(open(&temp_dir("resp-keys")));
+    let output = resp_session(
+        &db,
+        &[
+            &["SET", "user:1", "a"],
+            &["SET", "user:22", "b"],
+            &["SET", "other", "c"],
+            &["DEL", "user:1"],
+            &["KEYS", "user:*"],
+            &["KEYS", "*"],
+            &["KEYS", "user:?"],
+        ],
+    )
+    .await;
+    assert_eq!(
+        output,
+        "+OK\r\n+OK\r\n+OK\r\n:1\r\n\
+         *1\r\n$7\r\nuser:22\r\n\
+         *2\r\n$5\r\nother\r\n$7\r\nuser:22\r\n\
+         *0\r\n"
+    );
+}
+
+/// Sends `input` over one HTTP connection and returns the status and body of
+/// each response.
+async fn http_session(db: &Arc<Db<SSTableEngine>>, input: &[u8]) -> Vec<(u16, String)> {
+    let mut output = Vec::new();
+    http::serve(&mut &input[..], &mut output, db).await.unwrap();
+
+    let mut responses = Vec::new();
+    let mut rest = String::from_utf8(output).unwrap();
+    while !rest.is_empty() {
+        let (head, tail) = rest.split_once("\r\n\r\n").unwrap();
+        let status = head[9..12].parse().unwrap();
+        let len: usize = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        responses.push((status, tail[..len].to_string()));
+        rest = tail[len..].to_string();
+    }
+    responses
+}
+
+fn http_request(method: &str, target: &str, body: &str) -> String {
+    format!(
+        "{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        target,
+        body.len(),
+        body
+    )
+}
+
+#[tokio::test]
+async fn http_keys_are_percent_decoded_and_listed_encoded() {
+    let db = Arc::new(open(&temp_dir("http-binary")));
+    let input = [
+        http_request("PUT", "/kv/%FF%00a%20b", "binary"),
+        http_request("GET", "/kv/%FF%00a%20b", ""),
+        http_request("GET", "/kv", ""),
+        http_request("DELETE", "/kv/%FF%00a%20b", ""),
+        http_request("GET", "/kv/%FF%00a%20b", ""),
+    ]
+    .concat();
+    let responses = http_session(&db, input.as_bytes()).await;
+    assert_eq!(responses[0], (204, String::new()));
+    assert_eq!(responses[1], (200, "binary".to_string()));
+    assert_eq!(
+        responses[2],
+        (200, r#"{"keys":["%FF%00a%20b"]}"#.to_string())
+    );
+    assert_eq!(responses[3], (204, String::new()));
+    assert_eq!(responses[4].0, 404);
+    assert!(
+        db.handle_get(&[b"GET".to_vec(), b"\xff\0a b".to_vec()])
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn http_list_takes_a_prefix_and_limit() {
+    let db = Arc::new(open(&temp_dir("http-list")));
+    let mut input = String::new();
+    for key in ["user:1", "user:2", "user:3", "other"] {
+        input.push_str(&http_request("PUT", &format!("/kv/{}", key), "v"));
+    }
+    input.push_str(&http_request("GET", "/kv?prefix=user%3A&limit=2", ""));
+    input.push_str(&http_request("GET", "/kv?prefix=user:", ""));
+    input.push_str(&http_request("GET", "/kv?limit=0", ""));
+    input.push_str(&http_request("GET", "/kv?limit=-1", ""));
+    let responses = http_session(&db, input.as_bytes()).await;
+    assert_eq!(
+        responses[4],
+        (200, r#"{"keys":["user:1","user:2"]}"#.to_string())
+    );
+    assert_eq!(
+        responses[5],
+        (200, r#"{"keys":["user:1","user:2","user:3"]}"#.to_string())
+    );
+    assert_eq!(responses[6], (200, r#"{"keys":[]}"#.to_string()));
+    assert_eq!(responses[7].0, 400);
+}
+
+#[tokio::test]
+async fn http_batch_runs_ops_in_order() {
+    let db = Arc::new(open(&temp_dir("http-batch")));
+    let body = r#"{"ops": [
+        {"op": "put", "key": "a", "value": "1"},
+        {"op": "get", "key": "a"},
+        {"op": "put", "key": "%FF", "value": "%00%01", "encoding": "percent"},
+        {"op": "get", "key": "%FF", "encoding": "percent"},
+        {"op": "delete", "key": "a"},
+        {"op": "get", "key": "a"}
+    ]}"#;
+    let responses = http_session(&db, http_request("POST", "/batch", body).as_bytes()).await;
+    let (status, body) = &responses[0];
+    assert_eq!(*status, 200);
+    let results: serde_json::Value = serde_json::from_str(body).unwrap();
+    let results = results["results"].as_array().unwrap();
+    assert_eq!(results[0], serde_json::json!({"ok": true}));
+    assert_eq!(results[1], serde_json::json!({"value": "1"}));
+    assert_eq!(results[2], serde_json::json!({"ok": true}));
+    assert_eq!(
+        results[3],
+        serde_json::json!({"value": "%00%01", "encoding": "percent"})
+    );
+    assert_eq!(results[4], serde_json::json!({"ok": true}));
+    assert_eq!(results[5]["error"]["code"], "DELETED");
+
+    let responses = http_session(&db, http_request("POST", "/batch", "{").as_bytes()).await;
+    assert_eq!(responses[0].0, 400);
+}
+
+#[tokio::test]
+async fn http_oversized_requests_are_rejected() {
+    let db = Arc::new(open(&temp_dir("http-limits")));
+    let long = "a".repeat(http::MAX_LINE_BYTES);
+
+    let request_line = format!("GET /kv/{} HTTP/1.1\r\n\r\n", long);
+    let responses = http_session(&db, request_line.as_bytes()).await;
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].0, 400);
+
+    let header = format!("GET /kv/a HTTP/1.1\r\nX-Long: {}\r\n\r\n", long);
+    let responses = http_session(&db, header.as_bytes()).await;
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].0, 431);
+
+    let many = "X-A: b\r\n".repeat(http::MAX_HEADERS + 1);
+    let headers = format!("GET /kv/a HTTP/1.1\r\n{}\r\n", many);
+    let responses = http_session(&db, headers.as_bytes()).await;
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].0, 431);
+
+    // Right at the limits is fine
+    let many = "X-A: b\r\n".repeat(http::MAX_HEADERS);
+    let headers = format!("GET /kv/a HTTP/1.1\r\n{}\r\n", many);
+    let responses = http_session(&db, headers.as_bytes()).await;
+    assert_eq!(responses[0].0, 404);
+}
@@ -0,0 +1,272 @@
+use std::{io, sync::Arc};
+
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    common::{command_type::CommandType, db_errors::DbError},
+    db::Db,
+    protocol::{is_fatal, read_request, write_literal},
+    storage_engine::engine::Engine,
+};
+
+/// Connections start on version 1, the original protocol, so existing
+/// clients keep working. `HELLO 2` switches to the current version.
+const LEGACY_VERSION: u32 = 1;
+const LATEST_VERSION: u32 = 2;
+
+/// Result of one request, sent in the format of the connection's version.
+enum Outcome {
+    Inserted(Vec<u8>),
+    Deleted,
+    Value(Vec<u8>),
+    Keys(Vec<Vec<u8>>),
+    Stats(Vec<(&'static str, u64)>),
+    Integer(i64),
+    Hello(u32),
+    Failed(DbError),
+    /// An error from the protocol rather than the db, with its code.
+    Error(&'static str, String),
+}
+
+/// Serves a connection speaking the text protocol until it disconnects.
+///
+/// Version 2 replies are one of:
+/// - `+OK` for a successful write
+/// - `$<len>` followed by a newline and `len` bytes of value
+/// - `*<n>` followed by `n` bulk replies
+/// - `:<n>` for a number
+/// - `-ERR <CODE> <message>`, where `CODE` is stable (see `DbError::code`)
+///
+/// Every reply ends with a newline. Version 1 replies are the original
+/// free-form lines.
+pub async fn serve<E, R, W>(reader: &mut R, writer: &mut W, db: &Arc<Db<E>>) -> io::Result<()>
+where
+    E: Engine + Send + Sync + 'static,
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut version = LEGACY_VERSION;
+    let mut buf = Vec::new();
+    loop {
+        let parts = match read_request(reader, version >= 2).await {
+            Ok(Some(parts)) => parts,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                buf.clear();
+                encode(
+                    &Outcome::Error("PROTOCOL_ERROR", e.to_string()),
+                    version,
+                    &mut buf,
+                );
+                writer.write_all(&buf).await?;
+                if is_fatal(&e) {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if parts.is_empty() {
+            continue;
+        }
+
+        let command = String::from_utf8_lossy(&parts[0]).into_owned();
+        let outcome = match CommandType::command_type_from_str(&command) {
+            None => Outcome::Error("UNKNOWN_COMMAND", format!("Unknown command {}", command)),
+            // Only changes the connection, so it doesn't need the db
+            Some(CommandType::Hello) => hello(&parts, &mut version),
+            Some(command_type) => db
+                .run_write(move |db| execute(command_type, &parts, version, db))
+                .await
+                .unwrap_or_else(Outcome::Failed),
+        };
+
+        buf.clear();
+        encode(&outcome, version, &mut buf);
+        writer.write_all(&buf).await?;
+    }
+}
+
+/// Runs a command for `Db::run_write`.
+fn execute<E: Engine>(
+    command_type: CommandType,
+    parts: &[Vec<u8>],
+    version: u32,
+    db: &Db<E>,
+) -> (Outcome, Option<u64>) {
+    match command_type {
+        // Version 1 joins extra arguments into the value, so it can't take
+        // options. From version 2 on values with spaces are quoted.
+        CommandType::Set if version == LEGACY_VERSION && parts.len() > 3 => {
+            let joined = [parts[0].clone(), parts[1].clone(), parts[2..].join(&b' ')];
+            execute(command_type, &joined, version, db)
+        }
+        CommandType::Set => match db.handle_set(parts) {
+            Ok(seq) => (Outcome::Inserted(parts[1].clone()), Some(seq)),
+            Err(e) => (Outcome::Failed(e), None),
+        },
+        CommandType::Get => match db.handle_get(parts) {
+            Ok(value) => (Outcome::Value(value), None),
+            Err(e) => (Outcome::Failed(e), None),
+        },
+        CommandType::GetKeys => match db.keys(b"", usize::MAX) {
+            Ok(keys) => (Outcome::Keys(keys), None),
+            Err(e) => (Outcome::Failed(e), None),
+        },
+        CommandType::Stats => (Outcome::Stats(db.stats()), None),
+        CommandType::Delete => match db.handle_delete(parts) {
+            Ok(seq) => (Outcome::Deleted, Some(seq)),
+            Err(e) => (Outcome::Failed(e), None),
+        },
+        CommandType::Expire => match db.handle_expire(parts) {
+            Ok(Some(seq)) => (Outcome::Integer(1), Some(seq)),
+            Ok(None) => (Outcome::Integer(0), None),
+            Err(e) => (Outcome::Failed(e), None),
+        },
+        CommandType::Ttl => match db.handle_ttl(parts) {
+            Ok(ttl) => (Outcome::Integer(ttl), None),
+            Err(e) => (Outcome::Failed(e), None),
+        },
+        CommandType::Persist => match db.handle_persist(parts) {
+            Ok(Some(seq)) => (Outcome::Integer(1), Some(seq)),
+            Ok(None) => (Outcome::Integer(0), None),
+            Err(e) => (Outcome::Failed(e), None),
+        },
+        CommandType::Incr | CommandType::Decr | CommandType::IncrBy => {
+            let result = match command_type {
+                CommandType::Incr => db.handle_incr(parts),
+                CommandType::Decr => db.handle_decr(parts),
+                _ => db.handle_incr_by(parts),
+            };
+            match result {
+                Ok((value, seq)) => (Outcome::Integer(value), Some(seq)),
+                Err(e) => (Outcome::Failed(e), None),
+            }
+        }
+        CommandType::IncrByFloat => match db.handle_incr_by_float(parts) {
+            Ok((value, seq)) => (Outcome::Value(value), Some(seq)),
+            Err(e) => (Outcome::Failed(e), None),
+        },
+        CommandType::Cas => match db.handle_cas(parts) {
+            Ok(seq) => (Outcome::Inserted(parts[1].clone()), Some(seq)),
+            Err(e) => (Outcome::Failed(e), None),
+        },
+        CommandType::Hello => unreachable!("HELLO is answered without the db"),
+    }
+}
+
+/// `HELLO [version]`: switches the connection to `version` and describes the
+/// server in the format of that version.
+fn hello(parts: &[Vec<u8>], version: &mut u32) -> Outcome {
+    if let Some(requested) = parts.get(1) {
+        match std::str::from_utf8(requested)
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            Some(requested @ LEGACY_VERSION..=LATEST_VERSION) => *version = requested,
+            _ => {
+                return Outcome::Error(
+                    "UNSUPPORTED_VERSION",
+                    format!(
+                        "Supported protocol versions are {} to {}",
+                        LEGACY_VERSION, LATEST_VERSION
+                    ),
+                );
+            }
+        }
+    }
+    Outcome::Hello(*version)
+}
+
+fn encode(outcome: &Outcome, version: u32, buf: &mut Vec<u8>) {
+    if version == LEGACY_VERSION {
+        encode_legacy(outcome, buf);
+        return;
+    }
+
+    match outcome {
+        Outcome::Inserted(_) | Outcome::Deleted => buf.extend_from_slice(b"+OK\n"),
+        Outcome::Value(value) => encode_bulk(buf, value),
+        Outcome::Keys(keys) => {
+            buf.extend_from_slice(format!("*{}\n", keys.len()).as_bytes());
+            for key in keys {
+                encode_bulk(buf, key);
+            }
+        }
+        Outcome::Stats(stats) => {
+            buf.extend_from_slice(format!("*{}\n", stats.len()).as_bytes());
+            for (name, value) in stats {
+                encode_bulk(buf, format!("{}:{}", name, value).as_bytes());
+            }
+        }
+        Outcome::Integer(n) => buf.extend_from_slice(format!(":{}\n", n).as_bytes()),
+        Outcome::Hello(version) => {
+            let fields = [
+                "server".to_string(),
+                "mdb".to_string(),
+                "version".to_string(),
+                env!("CARGO_PKG_VERSION").to_string(),
+                "proto".to_string(),
+                version.to_string(),
+            ];
+            buf.extend_from_slice(format!("*{}\n", fields.len()).as_bytes());
+            for field in fields {
+                encode_bulk(buf, field.as_bytes());
+            }
+        }
+        Outcome::Failed(e) => encode_error(buf, e.code(), &e.to_string()),
+        Outcome::Error(code, message) => encode_error(buf, code, message),
+    }
+}
+
+fn encode_bulk(buf: &mut Vec<u8>, value: &[u8]) {
+    buf.extend_from_slice(format!("${}\n", value.len()).as_bytes());
+    buf.extend_from_slice(value);
+    buf.push(b'\n');
+}
+
+fn encode_error(buf: &mut Vec<u8>, code: &str, message: &str) {
+    // Messages can quote keys, which may hold newlines
+    let message = message.replace(['\r', '\n'], " ");
+    buf.extend_from_slice(format!("-ERR {} {}\n", code, message).as_bytes());
+}
+
+/// Version 1 replies, unchanged from before the protocol was versioned.
+fn encode_legacy(outcome: &Outcome, buf: &mut Vec<u8>) {
+    match outcome {
+        // Escaped so the reply stays on one line
+        Outcome::Inserted(key) => {
+            buf.extend_from_slice(format!("OK: inserted {}\n", key.escape_ascii()).as_bytes())
+        }
+        Outcome::Deleted => buf.extend_from_slice(b"OK: deleted\n"),
+        // Values and keys are sent as literals, so any bytes can come back
+        Outcome::Value(value) => {
+            write_literal(buf, value);
+            buf.push(b'\n');
+        }
+        Outcome::Keys(keys) => {
+            buf.extend_from_slice(format!("*{}", keys.len()).as_bytes());
+            for key in keys {
+                buf.push(b' ');
+                write_literal(buf, key);
+            }
+            buf.push(b'\n');
+        }
+        Outcome::Stats(stats) => {
+            let stats: Vec<String> = stats
+                .iter()
+                .map(|(name, value)| format!("{}:{}", name, value))
+                .collect();
+            buf.extend_from_slice(format!("{}\n", stats.join(" ")).as_bytes());
+        }
+        Outcome::Integer(n) => buf.extend_from_slice(format!("{}\n", n).as_bytes()),
+        Outcome::Hello(version) => {
+            buf.extend_from_slice(format!("OK: protocol {}\n", version).as_bytes())
+        }
+        Outcome::Failed(e) => buf.extend_from_slice(format!("ERR: {:?}\n", e).as_bytes()),
+        Outcome::Error("UNKNOWN_COMMAND", _) => buf.extend_from_slice(b"Invalid command\n"),
+        Outcome::Error(_, message) => {
+            buf.extend_from_slice(format!("ERR: {}\n", message).as_bytes())
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    config::{CompactionConfig, CompactionStrategyKind},
+    manifest::FileMeta,
+};
+
+/// Decides which SSTables to compact next.
+pub trait CompactionStrategy {
+    /// Picks the next compaction for the live files, given in manifest
+    /// order, or `None` if there is nothing to do.
+    fn pick(&self, files: &[Arc<FileMeta>]) -> Option<CompactionTask>;
+}
+
+/// Builds the strategy selected in the config.
+pub fn strategy_from_config(
+    config: &CompactionConfig,
+) -> Box<dyn CompactionStrategy + Send + Sync> {
+    match config.strategy {
+        CompactionStrategyKind::Leveled => Box::new(LeveledCompaction::new(config)),
+        CompactionStrategyKind::SizeTiered => Box::new(SizeTieredCompaction::new(config)),
+    }
+}
+
+/// Files to merge and the level their output goes to.
+pub struct CompactionTask {
+    /// Inputs, newest first: the picked files, then what they overlap in
+    /// `output_level`.
+    pub inputs: Vec<Arc<FileMeta>>,
+    pub output_level: u32,
+    /// Nothing older outside the inputs can hold any of the input keys, so
+    /// tombstones are no longer needed once their grace period is over.
+    pub bottommost: bool,
+}
+
+impl CompactionTask {
+    /// A single file with nothing to merge with can just change level.
+    pub fn is_trivial_move(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].level != self.output_level
+    }
+}
+
+/// LevelDB-style leveled compaction. L0 holds memtable flushes, which may
+/// overlap. Every deeper level holds files with disjoint key ranges, and may
+/// grow to `level_base_bytes * level_size_ratio^(n-1)` bytes before a file is
+/// pushed down into the next level.
+pub struct LeveledCompaction {
+    level0_file_trigger: usize,
+    level_base_bytes: u64,
+    level_size_ratio: u64,
+    max_levels: u32,
+    /// Largest key of the last file compacted out of each level, so the
+    /// next compaction of that level starts after it.
+    compact_pointers: Mutex<Vec<Option<Vec<u8>>>>,
+}
+
+impl LeveledCompaction {
+    pub fn new(config: &CompactionConfig) -> Self {
+        LeveledCompaction {
+            level0_file_trigger: config.level0_file_trigger.max(1),
+            level_base_bytes: config.level_base_bytes.max(1),
+            level_size_ratio: config.level_size_ratio.max(2),
+            max_levels: config.max_levels.max(2),
+            compact_pointers: Mutex::new(vec![None; config.max_levels.max(2) as usize]),
+        }
+    }
+
+    /// Size limit of a level below L0.
+    fn target_bytes(&self, level: u32) -> u64 {
+        self.level_base_bytes.saturating_mul(
+            self.level_size_ratio
+                .saturating_pow(level.saturating_sub(1)),
+        )
+    }
+}
+
+impl CompactionStrategy for LeveledCompaction {
+    /// Picks the most oversized level and the files to compact out of it, or
+    /// `None` if every level is within its limit.
+    fn pick(&self, files: &[Arc<FileMeta>]) -> Option<CompactionTask> {
+        let level_files = |level: u32| -> Vec<Arc<FileMeta>> {
+            files.iter().filter(|f| f.level == level).cloned().collect()
+        };
+
+        // Score each level by how far over its limit it is; the last level has no limit
+        let mut best: Option<(f64, u32)> = None;
+        for level in 0..self.max_levels - 1 {
+            let score = if level == 0 {
+                level_files(0).len() as f64 / self.level0_file_trigger as f64
+            } else {
+                let bytes: u64 = level_files(level).iter().map(|f| f.size_bytes).sum();
+                bytes as f64 / self.target_bytes(level) as f64
+            };
+            if score >= 1.0 && best.is_none_or(|(best_score, _)| score > best_score) {
+                best = Some((score, level));
+            }
+        }
+        let (_, level) = best?;
+
+        // L0 files overlap each other, so they all go down together
+        let picked = if level == 0 {
+            level_files(0)
+        } else {
+            let mut candidates = level_files(level);
+            candidates.sort_by(|a, b| a.smallest_key.cmp(&b.smallest_key));
+
+            let mut pointers = self.compact_pointers.lock().unwrap();
+            let pointer = &mut pointers[level as usize];
+            let file = candidates
+                .iter()
+                .find(|f| pointer.as_ref().is_none_or(|p| f.smallest_key > *p))
+                .or(candidates.first())?
+                .clone();
+            *pointer = Some(file.largest_key.clone());
+            vec![file]
+        };
+
+        let (smallest, largest) = key_range(&picked)?;
+        let output_level = level + 1;
+        let mut inputs = picked;
+        inputs.extend(
+            files
+                .iter()
+                .filter(|f| f.level == output_level && f.overlaps(&smallest, &largest))
+                .cloned(),
+        );
+
+        // Files pulled in from the output level can widen the range
+        let (smallest, largest) = key_range(&inputs)?;
+        let bottommost = !files
+            .iter()
+            .any(|f| f.level > output_level && f.overlaps(&smallest, &largest));
+
+        Some(CompactionTask {
+            inputs,
+            output_level,
+            bottommost,
+        })
+    }
+}
+
+/// Cassandra-style size-tiered compaction. Everything stays in L0, and once
+/// at least `min_threshold` sorted runs of similar size are found they are
+/// merged into one bigger run.
+///
+/// A run is a flush or the output of one compaction (all files with the same
+/// seq). Only runs that are adjacent in age are merged, so a merged run never
+/// jumps ahead of a newer run it didn't include.
+pub struct SizeTieredCompaction {
+    min_threshold: usize,
+    max_threshold: usize,
+    /// Runs join a bucket if their size is within this factor of its average.
+    bucket_ratio: f64,
+}
+
+impl SizeTieredCompaction {
+    pub fn new(config: &CompactionConfig) -> Self {
+        let min_threshold = config.min_threshold.max(2);
+        SizeTieredCompaction {
+            min_threshold,
+            max_threshold: config.max_threshold.max(min_threshold),
+            bucket_ratio: config.bucket_ratio.max(1.0),
+        }
+    }
+}
+
+impl CompactionStrategy for SizeTieredCompaction {
+    /// Picks the bucket of similarly sized runs with the smallest average size.
+    fn pick(&self, files: &[Arc<FileMeta>]) -> Option<CompactionTask> {
+        // Sorted runs, oldest first. Manifest order is newest first within L0.
+        let mut runs: Vec<(u64, Vec<Arc<FileMeta>>)> = Vec::new();
+        for file in files.iter().filter(|f| f.level == 0).rev() {
+            match runs.last_mut() {
+                Some((seq, run)) if *seq == file.seq => run.push(file.clone()),
+                _ => runs.push((file.seq, vec![file.clone()])),
+            }
+        }
+        let run_size = |run: &[Arc<FileMeta>]| run.iter().map(|f| f.size_bytes).sum::<u64>();
+
+        // Buckets of adjacent runs, as (first run, number of runs, average size)
+        let mut best: Option<(usize, usize, f64)> = None;
+        let mut start = 0;
+        while start < runs.len() {
+            let mut total = run_size(&runs[start].1) as f64;
+            let mut end = start + 1;
+            while end < runs.len() && end - start < self.max_threshold {
+                let average = total / (end - start) as f64;
+                let size = run_size(&runs[end].1) as f64;
+                if size < average / self.bucket_ratio || size > average * self.bucket_ratio {
+                    break;
+                }
+                total += size;
+                end += 1;
+            }
+
+            let len = end - start;
+            let average = total / len as f64;
+            if len >= self.min_threshold && best.is_none_or(|(_, _, best_avg)| average < best_avg) {
+                best = Some((start, len, average));
+            }
+            start = end;
+        }
+        let (start, len, _) = best?;
+
+        // Newest run first, so the merge keeps the newest version of a key.
+        // Runs were collected oldest file first; put each back in manifest
+        // order too, since adopted legacy tables all share seq 0 and overlap.
+        let inputs: Vec<Arc<FileMeta>> = runs[start..start + len]
+            .iter()
+            .rev()
+            .flat_map(|(_, run)| run.iter().rev().cloned())
+            .collect();
+
+        let (smallest, largest) = key_range(&inputs)?;
+        let bottommost = start == 0
+            && !files
+                .iter()
+                .any(|f| f.level > 0 && f.overlaps(&smallest, &largest));
+
+        Some(CompactionTask {
+            inputs,
+            output_level: 0,
+            bottommost,
+        })
+    }
+}
+
+/// Smallest and largest key over `files`.
+fn key_range(files: &[Arc<FileMeta>]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let smallest = files.iter().map(|f| &f.smallest_key).min()?;
+    let largest = files.iter().map(|f| &f.largest_key).max()?;
+    Some((smallest.clone(), largest.clone()))
+}
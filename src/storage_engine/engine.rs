@@ -1,12 +1,20 @@
 use std::collections::BTreeMap;
 
-use crate::common::db_errors::DbError;
+use crate::{
+    common::{db_errors::DbError, entry::Entry},
+    ende::table::{Record, StoredValue},
+};
 
 pub trait Engine {
     fn new(file_path: String) -> Self;
-    fn save_all(&self, map: &BTreeMap<String, String>) -> Result<(), DbError>;
-    fn save(&self, k: String, v: String) -> Result<(), DbError>;
-    fn load(&self) -> Result<BTreeMap<String, String>, DbError>;
-    fn get_value(&self, k: String) -> Result<String, DbError>;
+    /// Persist a frozen memtable whose newest record has WAL sequence `seq`.
+    fn save_all(&self, map: &BTreeMap<Vec<u8>, Entry>, seq: u64) -> Result<(), DbError>;
+    /// The newest value of `k`, even if it has expired.
+    fn get_value(&self, k: &[u8]) -> Result<StoredValue, DbError>;
+    /// The newest version of the first `limit` keys starting with `prefix`,
+    /// in key order. Deleted keys have a `None` value and count too.
+    fn scan(&self, prefix: &[u8], limit: usize) -> Result<Vec<Record>, DbError>;
     fn compact_sstables(&self) -> Result<(), DbError>;
+    /// Counters for operators, as `(name, value)` pairs.
+    fn stats(&self) -> Vec<(&'static str, u64)>;
 }
@@ -0,0 +1,72 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use crate::{
+    common::db_errors::DbError,
+    ende::table::{Record, StoredValue, TableIter},
+};
+
+/// Merges sorted table iterators into one sorted stream with a min-heap over
+/// the current key of each input. When a key is in several inputs only the
+/// record from the first of them is returned, so inputs go newest first.
+pub struct MergingIter {
+    inputs: Vec<TableIter>,
+    /// Current value of each input, for the key that is on the heap.
+    heads: Vec<Option<Option<StoredValue>>>,
+    heap: BinaryHeap<Reverse<(Vec<u8>, usize)>>,
+}
+
+impl MergingIter {
+    pub fn new(inputs: Vec<TableIter>) -> Result<Self, DbError> {
+        let mut merge = MergingIter {
+            heads: vec![None; inputs.len()],
+            inputs,
+            heap: BinaryHeap::new(),
+        };
+        for source in 0..merge.inputs.len() {
+            merge.advance(source)?;
+        }
+        Ok(merge)
+    }
+
+    /// Moves `source` to its next record and puts that on the heap.
+    fn advance(&mut self, source: usize) -> Result<(), DbError> {
+        match self.inputs[source].next() {
+            Some(record) => {
+                let (key, value) = record?;
+                self.heads[source] = Some(value);
+                self.heap.push(Reverse((key, source)));
+            }
+            None => self.heads[source] = None,
+        }
+        Ok(())
+    }
+
+    fn next_record(&mut self) -> Result<Option<Record>, DbError> {
+        // Ties on the key pop the lowest source, which is the newest table
+        let Some(Reverse((key, source))) = self.heap.pop() else {
+            return Ok(None);
+        };
+        let value = self.heads[source].take().unwrap_or(None);
+        self.advance(source)?;
+
+        // Older versions of the same key are shadowed
+        while let Some(Reverse((next_key, _))) = self.heap.peek()
+            && *next_key == key
+        {
+            let Some(Reverse((_, older))) = self.heap.pop() else {
+                break;
+            };
+            self.advance(older)?;
+        }
+
+        Ok(Some((key, value)))
+    }
+}
+
+impl Iterator for MergingIter {
+    type Item = Result<Record, DbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
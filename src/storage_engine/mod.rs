@@ -1,2 +1,7 @@
+pub mod compaction;
 pub mod engine;
+pub mod merge;
 pub mod sstable_engine;
+
+#[cfg(test)]
+mod tests;
@@ -1,158 +1,557 @@
-use std::cmp::Ordering;
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 use std::{
-    collections::BTreeMap,
-    fs::{self, File},
-    io::{Read, Seek, SeekFrom},
+    collections::{BTreeMap, HashSet},
+    fs,
 };
 
-use chrono::Utc;
-
-use crate::ende::{read_key_from_binary_file, write_btree_to_binary_file};
+use crate::cache::BlockCache;
+use crate::common::{
+    entry::{Entry, unix_millis},
+    fs::sync_dir,
+};
+use crate::config::{CompactionConfig, SSTableConfig};
+use crate::ende::table::{Damage, Record, StoredValue, Table, verify};
+use crate::ende::{SSTableWriter, write_btree_to_binary_file};
+use crate::manifest::{FileMeta, Manifest, VersionEdit};
+use crate::storage_engine::compaction::{CompactionStrategy, CompactionTask, strategy_from_config};
+use crate::storage_engine::merge::MergingIter;
 use crate::{common::db_errors::DbError, storage_engine::engine::Engine};
 
+/// Default size of compaction output files.
+pub const DEFAULT_TARGET_FILE_SIZE_BYTES: u64 = 2 * 1024 * 1024;
+
+/// Stat names for the number of files per level.
+const LEVEL_FILE_STATS: [&str; 8] = [
+    "l0_files", "l1_files", "l2_files", "l3_files", "l4_files", "l5_files", "l6_files", "l7_files",
+];
+
 pub struct SSTableEngine {
     pub file_path: String,
+    /// Bloom filter bits per key for new SSTables; 0 disables filters.
+    pub bloom_bits_per_key: usize,
+    /// Compaction starts a new output file once one reaches this size.
+    pub target_file_size_bytes: u64,
+    /// Minimum age of a tombstone before compaction may drop it.
+    pub tombstone_grace_secs: u64,
+    /// Parsed SSTable indexes and data blocks, by file name.
+    cache: BlockCache,
+    /// Which SSTables are live and in what order they are read.
+    manifest: Manifest,
+    /// Compaction inputs that are no longer live but may still be read
+    /// through an older `live_files` snapshot.
+    obsolete: Mutex<Vec<Arc<FileMeta>>>,
+    /// Picks which files to compact.
+    strategy: Box<dyn CompactionStrategy + Send + Sync>,
+    bloom_checks: AtomicU64,
+    bloom_skips: AtomicU64,
+    bytes_flushed: AtomicU64,
+    compactions: AtomicU64,
+    compaction_bytes_read: AtomicU64,
+    compaction_bytes_written: AtomicU64,
 }
 
-impl Engine for SSTableEngine {
-    fn new(file_path: String) -> Self {
-        SSTableEngine { file_path }
+impl SSTableEngine {
+    pub fn with_config(
+        file_path: String,
+        config: &SSTableConfig,
+        compaction: &CompactionConfig,
+    ) -> Result<Self, DbError> {
+        let manifest = if Manifest::exists(&file_path) {
+            Manifest::open(&file_path)?
+        } else {
+            // The manifest is only written once the existing tables are
+            // listed, so a start that fails before then adopts them again
+            Manifest::create(&file_path, legacy_files(&file_path)?)?
+        };
+        let engine = SSTableEngine {
+            manifest,
+            obsolete: Mutex::new(Vec::new()),
+            file_path,
+            bloom_bits_per_key: config.bloom_bits_per_key,
+            target_file_size_bytes: config.target_file_size_bytes,
+            tombstone_grace_secs: compaction.tombstone_grace_secs,
+            cache: BlockCache::new(config.block_cache_bytes),
+            strategy: strategy_from_config(compaction),
+            bloom_checks: AtomicU64::new(0),
+            bloom_skips: AtomicU64::new(0),
+            bytes_flushed: AtomicU64::new(0),
+            compactions: AtomicU64::new(0),
+            compaction_bytes_read: AtomicU64::new(0),
+            compaction_bytes_written: AtomicU64::new(0),
+        };
+
+        engine.remove_orphans()?;
+        Ok(engine)
     }
 
-    fn compact_sstables(&self) -> Result<(), DbError> {
-        let files_to_compact = get_sstable_files(&self.file_path)?;
-        let mut merged_data: BTreeMap<String, String> = BTreeMap::new();
+    /// Deletes what a crash can leave behind: unfinished `.tmp` files, and
+    /// SSTables that a flush or compaction installed but never committed to
+    /// the manifest (numbered past every committed file). Tables a compaction
+    /// removed but didn't get to delete are handled by `Manifest::open`. Any
+    /// other `.db` file isn't ours to delete, so it is only reported.
+    fn remove_orphans(&self) -> Result<(), DbError> {
+        let live: HashSet<String> = self
+            .manifest
+            .live_files()
+            .iter()
+            .map(|f| f.name.clone())
+            .collect();
+        let next_file_number = self.manifest.next_file_number();
+
+        let entries =
+            fs::read_dir(&self.file_path).map_err(|e| DbError::LoadFailed(e.to_string()))?;
+        let mut removed = 0;
+        for entry in entries {
+            let entry = entry.map_err(|e| DbError::LoadFailed(e.to_string()))?;
+            let Some(filename) = entry.file_name().to_str().map(String::from) else {
+                continue;
+            };
 
-        for filename in &files_to_compact {
-            let full_path = format!("{}/{}", self.file_path, filename);
-            let file = File::open(&full_path)
-                .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-            let metadata = file
-                .metadata()
-                .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-            let file_len = metadata.len();
+            if !entry.path().is_file() || live.contains(&filename) {
+                continue;
+            }
+            let orphan = filename.ends_with(".tmp")
+                || table_number(&filename).is_some_and(|n| n >= next_file_number);
+            if !orphan {
+                if filename.ends_with(".db") {
+                    println!("Leaving unknown file {} in {}", filename, self.file_path);
+                }
+                continue;
+            }
+            fs::remove_file(entry.path()).map_err(|e| DbError::LoadFailed(e.to_string()))?;
+            removed += 1;
+        }
 
-            // read footer (to get index offset)
-            let mut f = file;
-            f.seek(SeekFrom::Start(file_len - 16))
-                .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-            let mut buf = [0u8; 8];
-            f.read_exact(&mut buf)
-                .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-            let index_offset = u64::from_be_bytes(buf);
+        if removed > 0 {
+            println!("Removed {} orphaned files from {}", removed, self.file_path);
+            sync_dir(&self.file_path)?;
+        }
+        Ok(())
+    }
 
-            // jump to index section
-            f.seek(SeekFrom::Start(index_offset))
-                .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
+    /// Writes `map` to a new SSTable and returns its manifest entry. The file
+    /// isn't live until the entry is applied to the manifest.
+    fn write_table(
+        &self,
+        map: &BTreeMap<Vec<u8>, Entry>,
+        level: u32,
+        seq: u64,
+    ) -> Result<FileMeta, DbError> {
+        let (Some(smallest_key), Some(largest_key)) = (map.keys().next(), map.keys().next_back())
+        else {
+            return Err(DbError::SSTableWriteFailed("empty sstable".to_string()));
+        };
+
+        let number = self.manifest.new_file_number();
+        let name = table_name(number);
+        let full_path = format!("{}/{}", self.file_path, name);
+        write_btree_to_binary_file(map, &full_path, self.bloom_bits_per_key)?;
+
+        Ok(FileMeta {
+            number,
+            name,
+            level,
+            smallest_key: smallest_key.clone(),
+            largest_key: largest_key.clone(),
+            seq,
+            size_bytes: file_size(&full_path),
+            created_at: unix_now(),
+        })
+    }
 
-            let index_end = file_len - 16;
-            while f.stream_position().unwrap() < index_end {
-                // read key_len
-                let mut key_len_buf = [0u8; 4];
-                f.read_exact(&mut key_len_buf)
-                    .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-                let key_len = u32::from_be_bytes(key_len_buf) as usize;
-
-                // read key
-                let mut key_buf = vec![0u8; key_len];
-                f.read_exact(&mut key_buf)
-                    .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-                let key = String::from_utf8(key_buf)
-                    .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-
-                // read offset
-                let mut off_buf = [0u8; 8];
-                f.read_exact(&mut off_buf)
-                    .map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
-                let _offset = u64::from_be_bytes(off_buf);
-
-                // get value from this SSTable
-                match read_key_from_binary_file(&full_path, &key) {
-                    Ok(value) => {
-                        if !value.contains("___________TOMBSTONE________________") {
-                            // only keep if key is not yet merged (newer SSTables come first)
-                            if !merged_data.contains_key(&key) {
-                                merged_data.insert(key, value);
-                            }
-                        }
-                    }
-                    Err(DbError::TombStoneFound) => {
-                        // skip tombstones
-                    }
-                    Err(_) => {
-                        // ignore corrupted key/value gracefully
-                        continue;
-                    }
-                }
+    /// Streams the inputs of `task` through a k-way merge into SSTables of
+    /// about `target_file_size_bytes` each in the output level, pushing each
+    /// finished file onto `outputs`. Tombstones are dropped only if nothing
+    /// outside the inputs can hold an older version of the key and they are
+    /// past the grace period. Expired values are treated as tombstones.
+    fn merge_into(
+        &self,
+        task: &CompactionTask,
+        seq: u64,
+        outputs: &mut Vec<FileMeta>,
+    ) -> Result<(), DbError> {
+        let mut iters = Vec::with_capacity(task.inputs.len());
+        for file in &task.inputs {
+            // A damaged input would be deleted with the rest, so stop instead
+            iters.push(self.table(&file.name)?.iter()?);
+        }
+
+        // Tombstone age isn't tracked per record, so go by the newest input
+        let created_at = task.inputs.iter().map(|f| f.created_at).max().unwrap_or(0);
+        let drop_tombstones =
+            task.bottommost && unix_now().saturating_sub(created_at) >= self.tombstone_grace_secs;
+
+        let now = unix_millis();
+        let mut output: Option<(u64, SSTableWriter)> = None;
+        for record in MergingIter::new(iters)? {
+            let (key, value) = record?;
+            // Written as a tombstone unless dropped, so an older version
+            // further down can't reappear
+            let value = value.filter(|v| !v.is_expired(now));
+            if value.is_none() && drop_tombstones {
+                continue;
+            }
+
+            let (_, writer) = match &mut output {
+                Some(output) => output,
+                None => output.insert(self.new_table()?),
+            };
+            match value {
+                Some(value) => writer.add(&key, Some(&value.data), value.expires_at)?,
+                None => writer.add(&key, None, None)?,
+            }
+
+            if writer.bytes_written() >= self.target_file_size_bytes
+                && let Some((number, writer)) = output.take()
+            {
+                outputs.push(self.finish_table(
+                    number,
+                    writer,
+                    task.output_level,
+                    seq,
+                    created_at,
+                )?);
             }
         }
 
-        // Write merged data if any
-        if !merged_data.is_empty() {
-            let now = Utc::now();
-            let timestamp = now.timestamp();
-            let new_file_path = format!("{}/compacted_{}.db", self.file_path, timestamp);
+        if let Some((number, writer)) = output {
+            outputs.push(self.finish_table(number, writer, task.output_level, seq, created_at)?);
+        }
+        Ok(())
+    }
 
-            write_btree_to_binary_file(&merged_data, &new_file_path)?;
+    /// Runs one compaction and records it in the manifest.
+    fn run_compaction(&self, task: CompactionTask) -> Result<(), DbError> {
+        let seq = task.inputs.iter().map(|f| f.seq).max().unwrap_or(0);
+        let removed = task.inputs.iter().map(|f| f.number).collect();
+
+        if task.is_trivial_move() {
+            let mut file = task.inputs[0].as_ref().clone();
+            file.level = task.output_level;
+            return self.manifest.apply(VersionEdit {
+                added: vec![file],
+                removed,
+                ..VersionEdit::default()
+            });
+        }
 
-            // Remove old SSTables
-            for filename in files_to_compact {
-                let file_path = format!("{}/{}", self.file_path, filename);
-                fs::remove_file(file_path)
-                    .map_err(|e| DbError::SSTableWriteFailed(e.to_string()))?;
+        let mut outputs = Vec::new();
+        if let Err(e) = self.merge_into(&task, seq, &mut outputs) {
+            // Outputs were never live, so they can just go
+            for file in outputs {
+                let _ = fs::remove_file(format!("{}/{}", self.file_path, file.name));
             }
+            return Err(e);
         }
 
+        let bytes_read: u64 = task.inputs.iter().map(|f| f.size_bytes).sum();
+        let bytes_written: u64 = outputs.iter().map(|f| f.size_bytes).sum();
+        self.manifest.apply(VersionEdit {
+            added: outputs,
+            removed,
+            ..VersionEdit::default()
+        })?;
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        self.compaction_bytes_read
+            .fetch_add(bytes_read, Ordering::Relaxed);
+        self.compaction_bytes_written
+            .fetch_add(bytes_written, Ordering::Relaxed);
+
+        self.obsolete.lock().unwrap().extend(task.inputs);
+        self.delete_obsolete();
         Ok(())
     }
-    
 
-    fn save_all(&self, map: &std::collections::BTreeMap<String, String>) -> Result<(), DbError> {
-        let now = Utc::now();
-        let timestamp = now.timestamp();
+    /// Next compaction to run. The snapshot it is picked from is dropped
+    /// before the compaction runs, so it doesn't hold on to the inputs.
+    fn pick_compaction(&self) -> Option<CompactionTask> {
+        self.strategy.pick(&self.manifest.live_files())
+    }
+
+    /// Deletes the compaction inputs no reader holds any more. Only the
+    /// manifest hands out new references to a file, and it dropped its own
+    /// when the file stopped being live, so one held only here is unused.
+    fn delete_obsolete(&self) {
+        self.obsolete.lock().unwrap().retain(|file| {
+            if Arc::strong_count(file) > 1 {
+                return true;
+            }
+            let file_path = format!("{}/{}", self.file_path, file.name);
+            if let Err(e) = fs::remove_file(&file_path) {
+                println!("Failed to delete {}: {}", file_path, e);
+            }
+            self.cache.invalidate(&file.name);
+            false
+        });
+    }
+
+    /// Starts a new SSTable with a fresh file number.
+    fn new_table(&self) -> Result<(u64, SSTableWriter), DbError> {
+        let number = self.manifest.new_file_number();
+        let full_path = format!("{}/{}", self.file_path, table_name(number));
+        Ok((
+            number,
+            SSTableWriter::create(&full_path, self.bloom_bits_per_key)?,
+        ))
+    }
+
+    /// Finishes a table from `new_table` and returns its manifest entry.
+    fn finish_table(
+        &self,
+        number: u64,
+        writer: SSTableWriter,
+        level: u32,
+        seq: u64,
+        created_at: u64,
+    ) -> Result<FileMeta, DbError> {
+        let smallest_key = writer.first_key().unwrap_or_default().to_vec();
+        let largest_key = writer.last_key().unwrap_or_default().to_vec();
+        let size_bytes = writer.finish()?;
+
+        Ok(FileMeta {
+            number,
+            name: table_name(number),
+            level,
+            smallest_key,
+            largest_key,
+            seq,
+            size_bytes,
+            created_at,
+        })
+    }
+
+    /// Returns an SSTable with its index loaded, from the cache if possible.
+    fn table(&self, filename: &str) -> Result<Arc<Table>, DbError> {
+        self.cache.table(filename, || {
+            Table::open(&format!("{}/{}", self.file_path, filename))
+        })
+    }
+}
 
-        let full_path = format!("{}/{}.db", self.file_path, timestamp);
+impl Engine for SSTableEngine {
+    fn new(file_path: String) -> Self {
+        SSTableEngine::with_config(
+            file_path,
+            &SSTableConfig::default(),
+            &CompactionConfig::default(),
+        )
+        .expect("Failed to open sstables")
+    }
 
-        write_btree_to_binary_file(map, &full_path)
+    fn stats(&self) -> Vec<(&'static str, u64)> {
+        let mut stats = vec![
+            (
+                "bloom_filter_checks",
+                self.bloom_checks.load(Ordering::Relaxed),
+            ),
+            (
+                "bloom_filter_skips",
+                self.bloom_skips.load(Ordering::Relaxed),
+            ),
+        ];
+        stats.extend(self.cache.stats());
+
+        // Bytes written to SSTables per byte flushed, in hundredths
+        let flushed = self.bytes_flushed.load(Ordering::Relaxed);
+        let compacted = self.compaction_bytes_written.load(Ordering::Relaxed);
+        let write_amplification = ((flushed + compacted) * 100)
+            .checked_div(flushed)
+            .unwrap_or(0);
+        stats.extend([
+            ("compactions", self.compactions.load(Ordering::Relaxed)),
+            ("bytes_flushed", flushed),
+            (
+                "compaction_bytes_read",
+                self.compaction_bytes_read.load(Ordering::Relaxed),
+            ),
+            ("compaction_bytes_written", compacted),
+            ("write_amplification_x100", write_amplification),
+        ]);
+
+        let files = self.manifest.live_files();
+        for (level, name) in LEVEL_FILE_STATS.iter().enumerate() {
+            let count = files.iter().filter(|f| f.level as usize == level).count();
+            if count > 0 {
+                stats.push((name, count as u64));
+            }
+        }
+        stats
     }
 
-    fn save(&self, _k: String, _v: String) -> Result<(), DbError> {
+    /// Runs compactions until the strategy has nothing left to do.
+    fn compact_sstables(&self) -> Result<(), DbError> {
+        while let Some(task) = self.pick_compaction() {
+            self.run_compaction(task)?;
+        }
+        // Inputs still being read when their compaction finished
+        self.delete_obsolete();
         Ok(())
     }
 
-    fn load(&self) -> Result<BTreeMap<String, String>, DbError> {
-        let map: BTreeMap<String, String> = BTreeMap::new();
-        Ok(map)
+    fn save_all(&self, map: &BTreeMap<Vec<u8>, Entry>, seq: u64) -> Result<(), DbError> {
+        let file = self.write_table(map, 0, seq)?;
+        self.bytes_flushed
+            .fetch_add(file.size_bytes, Ordering::Relaxed);
+        self.manifest.apply(VersionEdit {
+            added: vec![file],
+            ..VersionEdit::default()
+        })
     }
 
-    fn get_value(&self, k: String) -> Result<String, DbError> {
-        let files = get_sstable_files(&self.file_path)?;
-        for file in files {
-            let full_path = format!("{}/{}", self.file_path, file);
+    fn scan(&self, prefix: &[u8], limit: usize) -> Result<Vec<Record>, DbError> {
+        let mut iters = Vec::new();
+        for file in self.manifest.live_files() {
+            // A smallest key past the prefix without starting with it is past
+            // every key that does
+            if file.largest_key.as_slice() < prefix
+                || (file.smallest_key.as_slice() > prefix && !file.smallest_key.starts_with(prefix))
+            {
+                continue;
+            }
+            iters.push(self.table(&file.name)?.iter()?);
+        }
+
+        let mut records = Vec::new();
+        for record in MergingIter::new(iters)? {
+            let (key, value) = record?;
+            if key.as_slice() < prefix {
+                continue;
+            }
+            if !key.starts_with(prefix) || records.len() >= limit {
+                break;
+            }
+            records.push((key, value));
+        }
+        Ok(records)
+    }
+
+    fn get_value(&self, k: &[u8]) -> Result<StoredValue, DbError> {
+        for file in self.manifest.live_files() {
+            if !file.may_contain(k) {
+                continue;
+            }
+
+            // Older files may hold a stale value, so don't look past a file
+            // that can't be read
+            let table = self.table(&file.name)?;
+
+            if table.bloom.is_some() {
+                self.bloom_checks.fetch_add(1, Ordering::Relaxed);
+                if !table.may_contain(k) {
+                    self.bloom_skips.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            }
 
-            match read_key_from_binary_file(&full_path, &k) {
+            match table.get(k, &self.cache) {
                 Ok(val) => return Ok(val),
                 Err(e) => {
+                    // The newest version of the key is a delete
                     if matches!(e, DbError::TombStoneFound) {
-                        break;
+                        return Err(DbError::Deleted(String::from_utf8_lossy(k).into_owned()));
                     }
                     if matches!(e, DbError::KeyNotInFile) {
                         continue;
                     }
-                    println!("{:?}", e);
-                    continue;
+                    return Err(e);
                 }
             };
         }
 
         Err(DbError::KeyNotFound(format!(
             "Key not found for key: {}",
-            k
+            String::from_utf8_lossy(k)
         )))
     }
 }
 
+/// Check every SSTable in `file_dir` and return the damaged ranges per file.
+/// Files without damage are left out.
+pub fn verify_sstables(file_dir: &str) -> Result<Vec<(String, Vec<Damage>)>, DbError> {
+    let mut damaged = Vec::new();
+    for filename in get_sstable_files(file_dir)? {
+        let damage = verify(&format!("{}/{}", file_dir, filename))?;
+        if !damage.is_empty() {
+            damaged.push((filename, damage));
+        }
+    }
+    Ok(damaged)
+}
+
+fn unix_now() -> u64 {
+    chrono::Utc::now().timestamp().max(0) as u64
+}
+
+fn table_name(number: u64) -> String {
+    format!("{:06}.db", number)
+}
+
+/// Number of a table named by `table_name`, or `None` for other names.
+fn table_number(name: &str) -> Option<u64> {
+    name.strip_suffix(".db")?.parse().ok()
+}
+
+fn file_size(path: &str) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+/// Manifest entries for the SSTables in a data dir from before there was a
+/// manifest, keeping their old mtime order: the oldest file gets the lowest
+/// number. Unreadable files are reported and left out, and stay on disk.
+fn legacy_files(file_dir: &str) -> Result<Vec<FileMeta>, DbError> {
+    // A new data dir, created along with the manifest
+    if fs::metadata(file_dir).is_err() {
+        return Ok(Vec::new());
+    }
+    let mut filenames = get_sstable_files(file_dir)?;
+    filenames.reverse();
+
+    // Numbered past any file already named like a new table, so none of
+    // them is overwritten
+    let mut number = filenames
+        .iter()
+        .filter_map(|name| table_number(name))
+        .max()
+        .unwrap_or(0);
+
+    let mut files = Vec::new();
+    for name in filenames {
+        let path = format!("{}/{}", file_dir, name);
+        let (smallest_key, largest_key) = match Table::open(&path).and_then(|t| t.key_range()) {
+            Ok(Some(range)) => range,
+            Ok(None) => continue,
+            Err(e) => {
+                println!(
+                    "Not adding unreadable SSTable {} to the manifest: {}",
+                    name, e
+                );
+                continue;
+            }
+        };
+        number += 1;
+        files.push(FileMeta {
+            number,
+            smallest_key,
+            largest_key,
+            size_bytes: file_size(&path),
+            name,
+            level: 0,
+            seq: 0,
+            created_at: 0,
+        });
+    }
+
+    if !files.is_empty() {
+        println!("Adding {} existing SSTables to the manifest", files.len());
+    }
+    Ok(files)
+}
+
+/// Every `.db` file in `file_dir`, newest mtime first. Only used for files
+/// that aren't in a manifest yet; reads go through the manifest.
 pub fn get_sstable_files(file_dir: &str) -> Result<Vec<String>, DbError> {
     let entries = fs::read_dir(file_dir).map_err(|e| DbError::SSTableReadFailed(e.to_string()))?;
 
@@ -177,7 +576,7 @@ pub fn get_sstable_files(file_dir: &str) -> Result<Vec<String>, DbError> {
         }
     }
 
-    files_with_time.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    files_with_time.sort_by_key(|f| Reverse(f.1));
 
     let files: Vec<String> = files_with_time.into_iter().map(|(name, _)| name).collect();
 
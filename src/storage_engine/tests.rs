@@ -0,0 +1,652 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    common::{
+        db_errors::DbError,
+        entry::{Entry, unix_millis},
+    },
+    config::{CompactionConfig, CompactionStrategyKind, SSTableConfig},
+    ende::write_btree_to_binary_file,
+    manifest::Manifest,
+    storage_engine::{engine::Engine, sstable_engine::SSTableEngine},
+};
+
+/// Small xorshift generator, so failures replay with the same seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Fresh data directory under the system temp dir.
+fn temp_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("mdb-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir.to_string_lossy().into_owned()
+}
+
+fn sstable_config() -> SSTableConfig {
+    SSTableConfig {
+        target_file_size_bytes: 2 * 1024,
+        ..SSTableConfig::default()
+    }
+}
+
+fn leveled_config() -> CompactionConfig {
+    CompactionConfig {
+        strategy: CompactionStrategyKind::Leveled,
+        level0_file_trigger: 2,
+        level_base_bytes: 4 * 1024,
+        level_size_ratio: 2,
+        max_levels: 4,
+        ..CompactionConfig::default()
+    }
+}
+
+fn size_tiered_config() -> CompactionConfig {
+    CompactionConfig {
+        strategy: CompactionStrategyKind::SizeTiered,
+        min_threshold: 2,
+        max_threshold: 4,
+        bucket_ratio: 4.0,
+        ..CompactionConfig::default()
+    }
+}
+
+/// Checks every key against the model: `Some` is live, `None` was deleted,
+/// and keys missing from the model were never written.
+fn check(engine: &SSTableEngine, model: &BTreeMap<String, Option<String>>, keys: u64) {
+    for i in 0..keys {
+        let key = format!("key{:04}", i);
+        match (model.get(&key), engine.get_value(key.as_bytes())) {
+            (Some(Some(expected)), Ok(value)) => {
+                assert_eq!(value.data, expected.as_bytes(), "{}", key)
+            }
+            (Some(Some(_)), Err(e)) => panic!("{} should be live, got {:?}", key, e),
+            (Some(None), Ok(value)) => panic!("deleted {} reappeared as {:?}", key, value),
+            (Some(None), Err(DbError::Deleted(_) | DbError::KeyNotFound(_))) => {}
+            (None, Err(DbError::KeyNotFound(_))) => {}
+            (_, result) => panic!("{}: unexpected {:?}", key, result),
+        }
+    }
+}
+
+/// Random sets and deletes over a small key space, flushed as memtables and
+/// compacted after every flush, checked against a model after each round.
+fn run_workload(name: &str, compaction: CompactionConfig, seed: u64) {
+    const KEYS: u64 = 200;
+    let dir = temp_dir(name);
+    let engine = SSTableEngine::with_config(dir.clone(), &sstable_config(), &compaction).unwrap();
+
+    let mut rng = Rng(seed);
+    let mut model: BTreeMap<String, Option<String>> = BTreeMap::new();
+    let mut seq = 0;
+    for round in 0..60 {
+        let mut memtable = BTreeMap::new();
+        for _ in 0..1 + rng.below(80) {
+            // Keys above KEYS / 2 are written less often, so some stay unset
+            let range = KEYS / 2 + rng.below(KEYS / 2);
+            let key = format!("key{:04}", rng.below(range));
+            seq += 1;
+            if rng.below(3) == 0 {
+                memtable.insert(key.clone().into_bytes(), Entry::Delete { seq });
+                model.insert(key, None);
+            } else {
+                let value = format!("value-{}-{}", round, seq);
+                let entry = Entry::Put {
+                    seq,
+                    value: value.clone().into_bytes(),
+                    expires_at: None,
+                };
+                memtable.insert(key.clone().into_bytes(), entry);
+                model.insert(key, Some(value));
+            }
+        }
+
+        engine.save_all(&memtable, seq).unwrap();
+        engine.compact_sstables().unwrap();
+        check(&engine, &model, KEYS);
+    }
+
+    // Reopening replays the manifest rather than the compactions
+    drop(engine);
+    let engine = SSTableEngine::with_config(dir.clone(), &sstable_config(), &compaction).unwrap();
+    check(&engine, &model, KEYS);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn deleted_keys_stay_deleted_with_leveled_compaction() {
+    for seed in [1, 7, 42] {
+        run_workload(&format!("leveled-{}", seed), leveled_config(), seed);
+    }
+}
+
+#[test]
+fn deleted_keys_stay_deleted_with_size_tiered_compaction() {
+    for seed in [3, 11, 99] {
+        run_workload(&format!("stcs-{}", seed), size_tiered_config(), seed);
+    }
+}
+
+#[test]
+fn tombstones_are_kept_during_grace_period() {
+    let dir = temp_dir("grace");
+    let compaction = CompactionConfig {
+        tombstone_grace_secs: 3600,
+        ..leveled_config()
+    };
+    let engine = SSTableEngine::with_config(dir.clone(), &sstable_config(), &compaction).unwrap();
+
+    let mut live = BTreeMap::new();
+    for i in 0..50 {
+        let entry = Entry::Put {
+            seq: 1,
+            value: format!("value{}", i).into_bytes(),
+            expires_at: None,
+        };
+        live.insert(format!("key{:04}", i).into_bytes(), entry);
+    }
+    engine.save_all(&live, 1).unwrap();
+
+    let mut deletes = BTreeMap::new();
+    for i in 0..25 {
+        deletes.insert(
+            format!("key{:04}", i).into_bytes(),
+            Entry::Delete { seq: 2 },
+        );
+    }
+    engine.save_all(&deletes, 2).unwrap();
+    engine.compact_sstables().unwrap();
+
+    for i in 0..50 {
+        let key = format!("key{:04}", i);
+        match engine.get_value(key.as_bytes()) {
+            Err(DbError::Deleted(_)) if i < 25 => {}
+            Ok(value) if i >= 25 => assert_eq!(value.data, format!("value{}", i).into_bytes()),
+            result => panic!("{}: unexpected {:?}", key, result),
+        }
+    }
+    assert!(matches!(
+        engine.get_value(b"missing"),
+        Err(DbError::KeyNotFound(_))
+    ));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn expired_values_are_dropped_by_compaction() {
+    let dir = temp_dir("expiry");
+    let engine =
+        SSTableEngine::with_config(dir.clone(), &sstable_config(), &leveled_config()).unwrap();
+
+    // Every third key has expired, every third expires in an hour
+    let later = unix_millis() + 3600 * 1000;
+    let expires_at = |i: u64| match i % 3 {
+        0 => Some(1),
+        1 => Some(later),
+        _ => None,
+    };
+    for (seq, keys) in [(1, 0..30), (2, 30..60)] {
+        let mut memtable = BTreeMap::new();
+        for i in keys {
+            let entry = Entry::Put {
+                seq,
+                value: format!("value{}", i).into_bytes(),
+                expires_at: expires_at(i),
+            };
+            memtable.insert(format!("key{:04}", i).into_bytes(), entry);
+        }
+        engine.save_all(&memtable, seq).unwrap();
+    }
+
+    // Still stored until compaction reaches them
+    let stored = engine.get_value(b"key0000").unwrap();
+    assert!(stored.is_expired(unix_millis()));
+
+    engine.compact_sstables().unwrap();
+    for i in 0..60 {
+        let key = format!("key{:04}", i);
+        match engine.get_value(key.as_bytes()) {
+            Err(DbError::KeyNotFound(_)) if i % 3 == 0 => {}
+            Ok(value) if i % 3 != 0 => {
+                assert_eq!(value.data, format!("value{}", i).into_bytes());
+                assert_eq!(value.expires_at, expires_at(i), "{}", key);
+            }
+            result => panic!("{}: unexpected {:?}", key, result),
+        }
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn newest_legacy_table_wins_size_tiered_compaction() {
+    let dir = temp_dir("legacy-stcs");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // Two overlapping tables from before the manifest, the second one newer
+    let now = std::time::SystemTime::now();
+    for (name, value, age) in [("old.db", "old", 60), ("new.db", "new", 30)] {
+        let mut memtable = BTreeMap::new();
+        for i in 0..40 {
+            let entry = Entry::Put {
+                seq: 0,
+                value: format!("{}{}", value, i).into_bytes(),
+                expires_at: None,
+            };
+            memtable.insert(format!("key{:04}", i).into_bytes(), entry);
+        }
+        let path = format!("{}/{}", dir, name);
+        write_btree_to_binary_file(&memtable, &path, 0).unwrap();
+        let mtime = now - std::time::Duration::from_secs(age);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+
+    let engine =
+        SSTableEngine::with_config(dir.clone(), &sstable_config(), &size_tiered_config()).unwrap();
+
+    // A flush of about the same size, so both runs land in one bucket
+    let mut memtable = BTreeMap::new();
+    for i in 0..80 {
+        let entry = Entry::Put {
+            seq: 1,
+            value: format!("other{}", i).into_bytes(),
+            expires_at: None,
+        };
+        memtable.insert(format!("other{:04}", i).into_bytes(), entry);
+    }
+    engine.save_all(&memtable, 1).unwrap();
+    engine.compact_sstables().unwrap();
+
+    for i in 0..40 {
+        let key = format!("key{:04}", i);
+        let value = engine.get_value(key.as_bytes()).unwrap();
+        assert_eq!(value.data, format!("new{}", i).into_bytes(), "{}", key);
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn only_uncommitted_files_are_removed_on_open() {
+    let dir = temp_dir("orphans");
+    let open = || SSTableEngine::with_config(dir.clone(), &sstable_config(), &leveled_config());
+    let engine = open().unwrap();
+    let mut memtable = BTreeMap::new();
+    let entry = Entry::Put {
+        seq: 1,
+        value: b"value".to_vec(),
+        expires_at: None,
+    };
+    memtable.insert(b"key".to_vec(), entry);
+    engine.save_all(&memtable, 1).unwrap();
+    drop(engine);
+
+    // A flush that never reached the manifest, a half-written table and a
+    // file that isn't ours
+    for name in ["000007.db", "000008.db.tmp", "000001.sst.db"] {
+        std::fs::write(format!("{}/{}", dir, name), b"data").unwrap();
+    }
+    let engine = open().unwrap();
+    assert_eq!(engine.get_value(b"key").unwrap().data, b"value");
+
+    let exists = |name: &str| std::fs::exists(format!("{}/{}", dir, name)).unwrap();
+    assert!(!exists("000007.db"));
+    assert!(!exists("000008.db.tmp"));
+    assert!(exists("000001.sst.db"));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn damaged_manifest_edit_before_the_end_fails_open() {
+    let dir = temp_dir("manifest-damage");
+    let open = || SSTableEngine::with_config(dir.clone(), &sstable_config(), &leveled_config());
+    let engine = open().unwrap();
+    for seq in 1..=2 {
+        let mut memtable = BTreeMap::new();
+        let entry = Entry::Put {
+            seq,
+            value: b"value".to_vec(),
+            expires_at: None,
+        };
+        memtable.insert(format!("key{}", seq).into_bytes(), entry);
+        engine.save_all(&memtable, seq).unwrap();
+    }
+    drop(engine);
+
+    // Flip a byte in the first edit's payload; the second edit follows it
+    let path = format!("{}/MANIFEST", dir);
+    let mut buf = std::fs::read(&path).unwrap();
+    buf[10] ^= 0xff;
+    std::fs::write(&path, &buf).unwrap();
+    assert!(matches!(open(), Err(DbError::LoadFailed(_))));
+
+    // A torn last edit is dropped instead
+    let mut buf = std::fs::read(&path).unwrap();
+    buf[10] ^= 0xff;
+    buf.truncate(buf.len() - 3);
+    std::fs::write(&path, &buf).unwrap();
+    let engine = open().unwrap();
+    assert_eq!(engine.get_value(b"key1").unwrap().data, b"value");
+    assert!(matches!(
+        engine.get_value(b"key2"),
+        Err(DbError::KeyNotFound(_))
+    ));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn reads_during_compaction_see_every_key() {
+    const KEYS: u64 = 100;
+    let dir = temp_dir("read-race");
+    let engine =
+        SSTableEngine::with_config(dir.clone(), &sstable_config(), &leveled_config()).unwrap();
+    let write_round = |round: u64| {
+        let mut memtable = BTreeMap::new();
+        for i in 0..KEYS {
+            let entry = Entry::Put {
+                seq: round,
+                value: format!("value-{}", round).into_bytes(),
+                expires_at: None,
+            };
+            memtable.insert(format!("key{:04}", i).into_bytes(), entry);
+        }
+        engine.save_all(&memtable, round).unwrap();
+    };
+    write_round(1);
+
+    let done = std::sync::atomic::AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        for seed in 1..=4 {
+            let (engine, done) = (&engine, &done);
+            scope.spawn(move || {
+                let mut rng = Rng(seed);
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    let key = format!("key{:04}", rng.below(KEYS));
+                    if let Err(e) = engine.get_value(key.as_bytes()) {
+                        panic!("{} unreadable during compaction: {:?}", key, e);
+                    }
+                }
+            });
+        }
+
+        for round in 2..40 {
+            write_round(round);
+            engine.compact_sstables().unwrap();
+        }
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+    });
+
+    // Nothing reads the compaction inputs any more, so they are all gone
+    engine.compact_sstables().unwrap();
+    let tables = std::fs::read_dir(&dir)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("db".as_ref()))
+        .count();
+    drop(engine);
+    let engine =
+        SSTableEngine::with_config(dir.clone(), &sstable_config(), &leveled_config()).unwrap();
+    assert_eq!(
+        engine
+            .stats()
+            .iter()
+            .filter(|(name, _)| name.ends_with("_files"))
+            .map(|(_, n)| *n)
+            .sum::<u64>(),
+        tables as u64
+    );
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn unreadable_table_fails_the_read() {
+    let dir = temp_dir("unreadable");
+    let open = || SSTableEngine::with_config(dir.clone(), &sstable_config(), &leveled_config());
+    let engine = open().unwrap();
+    for (seq, value) in [(1, "old"), (2, "new")] {
+        let mut memtable = BTreeMap::new();
+        let entry = Entry::Put {
+            seq,
+            value: value.as_bytes().to_vec(),
+            expires_at: None,
+        };
+        memtable.insert(b"key".to_vec(), entry);
+        engine.save_all(&memtable, seq).unwrap();
+    }
+    drop(engine);
+
+    // An unknown version byte in the newer table must not let the older
+    // value show through
+    let path = format!("{}/000002.db", dir);
+    let mut buf = std::fs::read(&path).unwrap();
+    buf[8] ^= 0xff;
+    std::fs::write(&path, &buf).unwrap();
+
+    let engine = open().unwrap();
+    match engine.get_value(b"key") {
+        Err(DbError::KeyNotFound(_) | DbError::Deleted(_)) | Ok(_) => {
+            panic!("damaged table was skipped")
+        }
+        Err(_) => {}
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn scan_stops_at_limit() {
+    let dir = temp_dir("scan-limit");
+    let engine =
+        SSTableEngine::with_config(dir.clone(), &sstable_config(), &leveled_config()).unwrap();
+    let mut memtable = BTreeMap::new();
+    for i in 0..20 {
+        let entry = match i % 4 {
+            0 => Entry::Delete { seq: 1 },
+            _ => Entry::Put {
+                seq: 1,
+                value: b"value".to_vec(),
+                expires_at: None,
+            },
+        };
+        memtable.insert(format!("key{:04}", i).into_bytes(), entry);
+    }
+    memtable.insert(
+        b"other".to_vec(),
+        Entry::Put {
+            seq: 1,
+            value: b"value".to_vec(),
+            expires_at: None,
+        },
+    );
+    engine.save_all(&memtable, 1).unwrap();
+
+    // Tombstones count towards the limit
+    let records = engine.scan(b"key", 5).unwrap();
+    let keys: Vec<&[u8]> = records.iter().map(|(key, _)| key.as_slice()).collect();
+    assert_eq!(
+        keys,
+        [b"key0000", b"key0001", b"key0002", b"key0003", b"key0004"]
+    );
+    assert!(records[0].1.is_none() && records[4].1.is_none());
+    assert_eq!(engine.scan(b"key", usize::MAX).unwrap().len(), 20);
+    assert!(engine.scan(b"key", 0).unwrap().is_empty());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn undeleted_compaction_inputs_are_removed_before_the_manifest_is_rewritten() {
+    let dir = temp_dir("undeleted-inputs");
+    let engine =
+        SSTableEngine::with_config(dir.clone(), &sstable_config(), &leveled_config()).unwrap();
+    for seq in 1..=2 {
+        let mut memtable = BTreeMap::new();
+        let entry = Entry::Put {
+            seq,
+            value: b"value".to_vec(),
+            expires_at: None,
+        };
+        memtable.insert(format!("key{}", seq).into_bytes(), entry);
+        engine.save_all(&memtable, seq).unwrap();
+    }
+    let inputs: Vec<(String, Vec<u8>)> = ["000001.db", "000002.db"]
+        .iter()
+        .map(|name| {
+            let path = format!("{}/{}", dir, name);
+            (path.clone(), std::fs::read(&path).unwrap())
+        })
+        .collect();
+    engine.compact_sstables().unwrap();
+    drop(engine);
+
+    // As if the compaction had crashed after committing but before deleting
+    for (path, data) in &inputs {
+        std::fs::write(path, data).unwrap();
+    }
+    // Opening the manifest alone is enough, before any orphan cleanup
+    drop(Manifest::open(&dir).unwrap());
+    for (path, _) in &inputs {
+        assert!(!std::fs::exists(path).unwrap(), "{} was kept", path);
+    }
+
+    let engine =
+        SSTableEngine::with_config(dir.clone(), &sstable_config(), &leveled_config()).unwrap();
+    assert_eq!(engine.get_value(b"key1").unwrap().data, b"value");
+    assert_eq!(engine.get_value(b"key2").unwrap().data, b"value");
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn bloom_filters_skip_tables_without_the_key() {
+    let dir = temp_dir("bloom");
+    let engine =
+        SSTableEngine::with_config(dir.clone(), &sstable_config(), &leveled_config()).unwrap();
+
+    // Even keys only, so odd ones fall inside the table's key range
+    let mut memtable = BTreeMap::new();
+    for i in (0..1000).step_by(2) {
+        let entry = Entry::Put {
+            seq: 1,
+            value: format!("value{}", i).into_bytes(),
+            expires_at: None,
+        };
+        memtable.insert(format!("key{:04}", i).into_bytes(), entry);
+    }
+    engine.save_all(&memtable, 1).unwrap();
+
+    let stat = |name| {
+        engine
+            .stats()
+            .into_iter()
+            .find(|(stat, _)| *stat == name)
+            .unwrap()
+            .1
+    };
+    for i in (0..1000).step_by(2) {
+        let key = format!("key{:04}", i);
+        assert_eq!(
+            engine.get_value(key.as_bytes()).unwrap().data,
+            format!("value{}", i).into_bytes()
+        );
+    }
+    assert_eq!(stat("bloom_filter_checks"), 500);
+    assert_eq!(stat("bloom_filter_skips"), 0);
+
+    // key0999 is past the table's largest key, so the filter isn't needed
+    for i in (1..1000).step_by(2) {
+        let key = format!("key{:04}", i);
+        assert!(matches!(
+            engine.get_value(key.as_bytes()),
+            Err(DbError::KeyNotFound(_))
+        ));
+    }
+    assert_eq!(stat("bloom_filter_checks"), 999);
+    // About 1% get through at the default 10 bits per key
+    assert!(
+        stat("bloom_filter_skips") > 470,
+        "{}",
+        stat("bloom_filter_skips")
+    );
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn compaction_drops_deleted_tables_from_the_cache() {
+    let dir = temp_dir("cache");
+    let engine =
+        SSTableEngine::with_config(dir.clone(), &sstable_config(), &leveled_config()).unwrap();
+    let stat = |name| {
+        engine
+            .stats()
+            .into_iter()
+            .find(|(stat, _)| *stat == name)
+            .unwrap()
+            .1
+    };
+
+    // Two L0 tables, one short of the trigger until the second lands
+    for seq in 1..=2 {
+        let memtable: BTreeMap<Vec<u8>, Entry> = (0..20)
+            .map(|i| {
+                let entry = Entry::Put {
+                    seq,
+                    value: format!("value{}-{}", i, seq).into_bytes(),
+                    expires_at: None,
+                };
+                (format!("key{:04}", i * 2 + seq).into_bytes(), entry)
+            })
+            .collect();
+        engine.save_all(&memtable, seq).unwrap();
+    }
+    for i in 0..20 {
+        for seq in 1..=2 {
+            engine
+                .get_value(format!("key{:04}", i * 2 + seq).as_bytes())
+                .unwrap();
+        }
+    }
+    assert!(stat("block_cache_entries") >= 4);
+    let misses = stat("index_cache_misses");
+
+    engine.compact_sstables().unwrap();
+    assert!(stat("compactions") > 0);
+    // The inputs are gone, from the disk and from the cache
+    assert_eq!(stat("block_cache_entries"), 0);
+    assert_eq!(stat("block_cache_bytes"), 0);
+
+    for i in 0..20 {
+        for seq in 1..=2 {
+            let value = engine
+                .get_value(format!("key{:04}", i * 2 + seq).as_bytes())
+                .unwrap();
+            assert_eq!(value.data, format!("value{}-{}", i, seq).into_bytes());
+        }
+    }
+    // Read from the compaction's output, which had to be loaded fresh
+    assert!(stat("index_cache_misses") > misses);
+    assert!(stat("block_cache_entries") > 0);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
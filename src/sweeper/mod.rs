@@ -0,0 +1,34 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{db::Db, storage_engine::engine::Engine};
+
+/// Background task that deletes expired keys from the memtable every
+/// `interval`, so they don't sit in memory until something reads them.
+/// Compaction drops the ones already in SSTables.
+pub struct Sweeper<E: Engine + 'static + Send + Sync> {
+    db: Arc<Db<E>>,
+    interval: Duration,
+}
+
+impl<E: Engine + Send + Sync + 'static> Sweeper<E> {
+    pub fn new(db: Arc<Db<E>>, interval: Duration) -> Self {
+        Sweeper { db, interval }
+    }
+
+    pub fn start(&self) {
+        let db = self.db.clone();
+        let interval = self.interval;
+        println!("Expiry sweeper started");
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match db.run_blocking(|db| db.sweep_expired()).await {
+                    Ok(0) => {}
+                    Ok(swept) => println!("Deleted {} expired keys", swept),
+                    Err(e) => println!("Expiry sweep failed: {:?}", e),
+                }
+            }
+        });
+    }
+}
@@ -1,155 +1,477 @@
+pub mod record;
+
+#[cfg(test)]
+mod tests;
+
 use std::{
-    cmp::Ordering,
-    collections::BTreeMap,
     fs::{self, File, OpenOptions},
-    io::{BufRead, BufReader, BufWriter, Write},
-    time::{Duration, SystemTime},
+    io::{BufRead, BufReader, Write},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
-use chrono::Utc;
+use tokio::time::{Duration, sleep};
 
 use crate::{
-    common::{command_type::CommandType, db_errors::DbError},
-    storage_engine::engine::Engine,
+    common::{command_type::CommandType, db_errors::DbError, entry::Entry, fs::sync_dir},
+    config::{FsyncPolicy, WalConfig},
+    memtable::Memtable,
+    wal::record::{
+        MAX_RECORD_LEN, RECORD_OVERHEAD, SEGMENT_HEADER_LEN, WalRecord, decode_segment,
+        encode_record, segment_header,
+    },
 };
 
-pub struct Wal<E: Engine> {
+/// Size at which the active segment is sealed and a new one is started.
+pub const DEFAULT_SEGMENT_SIZE_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Largest key and value, together, that fit in one WAL record. Protocols
+/// reject larger arguments before they reach the db.
+pub const MAX_KEY_VALUE_LEN: usize = MAX_RECORD_LEN - RECORD_OVERHEAD;
+
+const SEGMENT_SUFFIX: &str = ".wal";
+const CHECKPOINT_FILE: &str = "checkpoint";
+
+/// Segmented write-ahead log.
+///
+/// Records are appended to a single active segment, named after the sequence
+/// number of its first record (`{first_seq:020}.wal`). Each record is a
+/// length-prefixed binary frame with a CRC32 (see `record`) tagged with a
+/// monotonic 64-bit log sequence number (LSN). Once the active segment grows
+/// past `segment_size_bytes` it is sealed and a new one is opened.
+///
+/// On open, a torn record at the end of the newest segment is truncated so
+/// later appends stay readable. Any other corrupt record fails the open
+/// rather than silently dropping the acknowledged writes after it.
+///
+/// Appends are written straight to the segment and made durable according to
+/// `fsync_policy`. A segment is always fsynced when it is sealed. Under `always`, callers wait on `sync_to` after releasing
+/// their locks, so one fsync covers every record appended while the previous
+/// one was in flight (group commit).
+///
+/// The `checkpoint` file holds the highest LSN already persisted to SSTables.
+/// Replay skips everything at or below it, and sealed segments fully covered
+/// by it are deleted.
+pub struct Wal {
     pub file_dir: String,
-    pub storage_engine: E,
+    pub segment_size_bytes: u64,
+    pub fsync_policy: FsyncPolicy,
+    state: Mutex<WalState>,
+    /// Held by the writer currently fsyncing on behalf of the group.
+    sync_lock: tokio::sync::Mutex<()>,
+    /// Highest sequence number known to be on disk.
+    synced_seq: AtomicU64,
 }
 
-impl<E: Engine> Wal<E> {
-    pub fn new(file_path: String, engine: E) -> Self {
-        Wal {
-            file_dir: file_path,
-            storage_engine: engine,
+struct WalState {
+    active: File,
+    active_first_seq: u64,
+    active_size: u64,
+    next_seq: u64,
+    checkpoint_seq: u64,
+}
+
+impl Wal {
+    pub fn new(file_path: String, config: &WalConfig) -> Result<Self, DbError> {
+        fs::create_dir_all(&file_path).map_err(|e| DbError::LoadFailed(e.to_string()))?;
+
+        let checkpoint_seq = read_checkpoint(&file_path)?;
+
+        // Find the highest LSN on disk so new records keep counting from it
+        let mut last_seq = checkpoint_seq;
+        let segments = list_segments(&file_path)?;
+        for (i, (_, segment)) in segments.iter().enumerate() {
+            let scan = read_segment(segment, |record| {
+                last_seq = last_seq.max(record.entry.seq())
+            })?;
+
+            // A crash mid-append leaves a torn record at the end of the newest
+            // segment. Cut it off, since appends go after it from now on.
+            if let Some(offset) = scan.corrupt_at {
+                if !scan.torn_tail || i != segments.len() - 1 {
+                    return Err(DbError::LoadFailed(format!(
+                        "corrupt WAL record in {} at offset {}",
+                        segment, offset
+                    )));
+                }
+                println!(
+                    "Truncating torn WAL record in {} at offset {}",
+                    segment, offset
+                );
+                truncate_segment(segment, scan.valid_len)?;
+            }
         }
+
+        let next_seq = last_seq + 1;
+        let (active, active_size) = open_segment(&file_path, next_seq)?;
+
+        let wal = Wal {
+            file_dir: file_path,
+            segment_size_bytes: config.segment_size_bytes,
+            fsync_policy: config.fsync,
+            state: Mutex::new(WalState {
+                active,
+                active_first_seq: next_seq,
+                active_size,
+                next_seq,
+                checkpoint_seq,
+            }),
+            sync_lock: tokio::sync::Mutex::new(()),
+            synced_seq: AtomicU64::new(last_seq),
+        };
+
+        wal.migrate_legacy_files()?;
+
+        Ok(wal)
     }
 
+    /// Appends a write of `value` to `key` (`None` for a delete), expiring at
+    /// `expires_at` if set, to the active segment and returns it as an entry
+    /// tagged with its sequence number. The record is not necessarily on disk
+    /// yet; see `sync_to`.
     pub fn store_wal(
         &self,
-        instruction: &str,
-        key: &String,
-        value: &String,
-    ) -> Result<(), DbError> {
-        let now = Utc::now();
-        let timestamp = now.timestamp();
-
-        let filename = format!("wal_{}.log", timestamp);
-        let full_file_path = format!("{}/{}", self.file_dir, filename);
-        let file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(&full_file_path)
-            .map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+        key: &[u8],
+        value: Option<Vec<u8>>,
+        expires_at: Option<u64>,
+    ) -> Result<Entry, DbError> {
+        // Replay would reject the record as corrupt
+        let value_len = value.as_ref().map_or(0, Vec::len);
+        if key.len() + value_len > MAX_KEY_VALUE_LEN {
+            return Err(DbError::InvalidCommand(
+                "Key and value are too large for a WAL record",
+            ));
+        }
 
-        let content = format!("{} {} {}\n", instruction, key, value);
+        let mut state = self.state.lock().unwrap();
+        let seq = state.next_seq;
 
-        let mut writer = BufWriter::new(&file);
+        let entry = match value {
+            Some(value) => Entry::Put {
+                seq,
+                value,
+                expires_at,
+            },
+            None => Entry::Delete { seq },
+        };
+        let content = encode_record(key, &entry);
 
-        writer
-            .write_all(content.as_bytes())
-            .map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
-        writer
-            .flush()
+        state
+            .active
+            .write_all(&content)
             .map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
-        file.sync_all()
+
+        state.next_seq += 1;
+        state.active_size += content.len() as u64;
+
+        if state.active_size >= self.segment_size_bytes {
+            self.roll_locked(&mut state)?;
+        }
+
+        Ok(entry)
+    }
+
+    /// Seals the active segment if it holds any records and returns the
+    /// sequence number of the last record written so far.
+    pub fn roll_segment(&self) -> Result<u64, DbError> {
+        let mut state = self.state.lock().unwrap();
+        if state.active_size > SEGMENT_HEADER_LEN {
+            self.roll_locked(&mut state)?;
+        }
+        Ok(state.next_seq - 1)
+    }
+
+    fn roll_locked(&self, state: &mut WalState) -> Result<(), DbError> {
+        // Nothing syncs a segment once it is no longer active. This happens
+        // even under `none`, so only the newest segment can end in a torn
+        // record after a crash.
+        state
+            .active
+            .sync_data()
             .map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+        self.synced_seq
+            .fetch_max(state.next_seq - 1, Ordering::AcqRel);
+
+        let (active, active_size) = open_segment(&self.file_dir, state.next_seq)?;
+        state.active = active;
+        state.active_first_seq = state.next_seq;
+        state.active_size = active_size;
         Ok(())
     }
 
-    pub fn play_wal_to_store(&self) -> Result<(), DbError> {
-        let mut map: BTreeMap<String, String> = BTreeMap::new();
+    /// Waits until the record with sequence number `seq` is durable, as far as
+    /// the fsync policy promises. Under `always`, the first waiter fsyncs for
+    /// everyone queued behind it.
+    pub async fn sync_to(self: &Arc<Self>, seq: u64) -> Result<(), DbError> {
+        if self.fsync_policy != FsyncPolicy::Always
+            || self.synced_seq.load(Ordering::Acquire) >= seq
+        {
+            return Ok(());
+        }
 
-        let files = self.get_wal_files_available_for_snapshot()?;
+        let _leader = self.sync_lock.lock().await;
 
-        if files.is_empty() {
+        // The previous leader's fsync may already have covered this record
+        if self.synced_seq.load(Ordering::Acquire) >= seq {
             return Ok(());
         }
 
-        for file in files {
-            let file = File::open(&file).map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+        let wal = self.clone();
+        tokio::task::spawn_blocking(move || wal.sync_active())
+            .await
+            .map_err(|e| DbError::WalStoreFailed(e.to_string()))?
+    }
 
-            let reader = BufReader::new(&file);
+    /// fsyncs the active segment. The state lock is only held to grab the
+    /// file handle, so other writers keep appending while the sync runs.
+    pub fn sync_active(&self) -> Result<(), DbError> {
+        let (file, last_seq) = {
+            let state = self.state.lock().unwrap();
+            let file = state
+                .active
+                .try_clone()
+                .map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+            (file, state.next_seq - 1)
+        };
 
-            for instruction_result in reader.lines() {
-                let instruction = instruction_result.map_err(|e| {
-                    DbError::WalStoreFailed(format!("failed to read lines. ERR {}", e))
-                })?;
-                self.store_wals_to_map(instruction.as_str(), &mut map);
-            }
+        if self.synced_seq.load(Ordering::Acquire) >= last_seq {
+            return Ok(());
         }
 
-        self.storage_engine.save_all(&map)?;
+        file.sync_data()
+            .map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+        self.synced_seq.fetch_max(last_seq, Ordering::AcqRel);
 
         Ok(())
     }
 
-    pub fn get_wal_files_available_for_snapshot(&self) -> Result<Vec<String>, DbError> {
-        let cutoff = SystemTime::now() - Duration::from_secs(5);
-        let entries =
-            fs::read_dir(&self.file_dir).map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+    /// Records that every record up to and including `seq` is persisted, then
+    /// deletes sealed segments that hold nothing newer.
+    pub fn checkpoint(&self, seq: u64) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        if seq <= state.checkpoint_seq {
+            return Ok(());
+        }
+
+        let tmp_path = format!("{}/{}.tmp", self.file_dir, CHECKPOINT_FILE);
+        let mut file =
+            File::create(&tmp_path).map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+        file.write_all(seq.to_string().as_bytes())
+            .map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+        file.sync_all()
+            .map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+        fs::rename(&tmp_path, format!("{}/{}", self.file_dir, CHECKPOINT_FILE))
+            .map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+        // The rename has to be on disk before the segments it covers go
+        sync_dir(&self.file_dir)?;
+
+        state.checkpoint_seq = seq;
+
+        // A sealed segment ends right before the next one starts
+        let segments = list_segments(&self.file_dir)?;
+        for window in segments.windows(2) {
+            let (first_seq, path) = &window[0];
+            let (next_first_seq, _) = &window[1];
+            if *first_seq >= state.active_first_seq || *next_first_seq - 1 > seq {
+                break;
+            }
+            if let Err(e) = fs::remove_file(path) {
+                println!("Failed to delete wal segment {}: {}", path, e);
+            }
+        }
 
-        // Collect (filename, modified_time)
-        let mut files_with_time: Vec<(String, SystemTime)> = vec![];
+        Ok(())
+    }
 
-        for potential_entry in entries {
-            let entry = potential_entry.map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+    pub fn last_checkpoint(&self) -> u64 {
+        self.state.lock().unwrap().checkpoint_seq
+    }
 
-            let path = entry.path();
+    /// Replays every record newer than the checkpoint into `memtable`,
+    /// including deletes, and returns the number of records recovered.
+    pub fn replay_into(&self, memtable: &mut Memtable) -> Result<usize, DbError> {
+        let checkpoint_seq = self.last_checkpoint();
+        let mut recovered = 0;
+
+        for (_, segment) in list_segments(&self.file_dir)? {
+            let scan = read_segment(&segment, |record| {
+                if record.entry.seq() > checkpoint_seq {
+                    memtable.insert(record.key, record.entry);
+                    recovered += 1;
+                }
+            })?;
+
+            // `new` already truncated a torn tail, so this is fresh damage
+            if let Some(offset) = scan.corrupt_at {
+                return Err(DbError::LoadFailed(format!(
+                    "corrupt WAL record in {} at offset {}",
+                    segment, offset
+                )));
+            }
+        }
 
-            if path.is_file() {
-                let metadata = entry
-                    .metadata()
-                    .map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+        Ok(recovered)
+    }
 
-                let filename = path
-                    .file_name()
-                    .and_then(|f| f.to_str())
-                    .unwrap_or("no_file")
-                    .to_string();
+    /// Moves records from the old one-file-per-second `wal_{timestamp}.log`
+    /// layout into the segmented log, then removes the old files.
+    fn migrate_legacy_files(&self) -> Result<(), DbError> {
+        let entries =
+            fs::read_dir(&self.file_dir).map_err(|e| DbError::LoadFailed(e.to_string()))?;
 
-                let file_modified_at = metadata
-                    .modified()
-                    .map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+        let mut legacy_files: Vec<(i64, String)> = vec![];
 
-                // Only include files older than cutoff
-                if file_modified_at < cutoff {
-                    files_with_time.push((format!("wal/{}", filename), file_modified_at));
+        for potential_entry in entries {
+            let entry = potential_entry.map_err(|e| DbError::LoadFailed(e.to_string()))?;
+            let path = entry.path();
+
+            if path.is_file()
+                && let Some(filename) = path.file_name().and_then(|f| f.to_str())
+                && let Some(ts) = filename
+                    .strip_prefix("wal_")
+                    .and_then(|f| f.strip_suffix(".log"))
+                    .and_then(|ts| ts.parse::<i64>().ok())
+            {
+                legacy_files.push((ts, format!("{}/{}", self.file_dir, filename)));
+            }
+        }
+
+        legacy_files.sort();
+
+        for (_, legacy_file) in &legacy_files {
+            let file = File::open(legacy_file).map_err(|e| DbError::LoadFailed(e.to_string()))?;
+
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(|e| DbError::LoadFailed(e.to_string()))?;
+                if let Some((instruction, rest)) = line.split_once(' ')
+                    && let Some((key, value)) = rest.split_once(' ')
+                {
+                    let value = match CommandType::command_type_from_str(instruction) {
+                        Some(CommandType::Set) => Some(value.as_bytes().to_vec()),
+                        Some(CommandType::Delete) => None,
+                        _ => {
+                            return Err(DbError::WalStoreFailed(format!(
+                                "unsupported wal instruction {}",
+                                instruction
+                            )));
+                        }
+                    };
+                    self.store_wal(key.as_bytes(), value, None)?;
                 }
             }
+
+            self.sync_active()?;
+            fs::remove_file(legacy_file).map_err(|e| DbError::LoadFailed(e.to_string()))?;
         }
 
-        files_with_time.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+        Ok(())
+    }
+}
 
-        let files: Vec<String> = files_with_time.into_iter().map(|(f, _)| f).collect();
+impl Wal {
+    /// Starts the once-per-second fsync task used by the `everysec` policy.
+    pub fn start_background_sync(self: &Arc<Self>) {
+        if self.fsync_policy != FsyncPolicy::Everysec {
+            return;
+        }
 
-        Ok(files)
+        let wal = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_secs(1)).await;
+                let synced = wal.clone();
+                let result = tokio::task::spawn_blocking(move || synced.sync_active())
+                    .await
+                    .map_err(|e| DbError::WalStoreFailed(e.to_string()))
+                    .and_then(|result| result);
+                if let Err(e) = result {
+                    println!("WAL background sync failed: {:?}", e);
+                }
+            }
+        });
     }
+}
 
-    pub fn store_wals_to_map(&self, instruction: &str, map: &mut BTreeMap<String, String>) {
-        let split_instruction: Vec<&str> = instruction.split(" ").collect();
+/// Returns `(first_seq, path)` for every segment in `file_dir`, oldest first.
+fn list_segments(file_dir: &str) -> Result<Vec<(u64, String)>, DbError> {
+    let entries = fs::read_dir(file_dir).map_err(|e| DbError::LoadFailed(e.to_string()))?;
 
-        if split_instruction.len() < 3 {
-            return;
+    let mut segments: Vec<(u64, String)> = vec![];
+
+    for potential_entry in entries {
+        let entry = potential_entry.map_err(|e| DbError::LoadFailed(e.to_string()))?;
+        let path = entry.path();
+
+        if path.is_file()
+            && let Some(filename) = path.file_name().and_then(|f| f.to_str())
+            && let Some(first_seq) = filename
+                .strip_suffix(SEGMENT_SUFFIX)
+                .and_then(|seq| seq.parse::<u64>().ok())
+        {
+            segments.push((first_seq, format!("{}/{}", file_dir, filename)));
         }
+    }
 
-        let instruction_type =
-            CommandType::command_type_from_str(split_instruction[0]).unwrap_or(CommandType::Get);
+    segments.sort();
 
-        let key = split_instruction[1];
+    Ok(segments)
+}
 
-        let val = split_instruction[2..].join(" ");
+/// Calls `f` with every valid record in a segment, stopping at the first
+/// torn or corrupt one.
+fn read_segment(path: &str, f: impl FnMut(WalRecord)) -> Result<record::SegmentScan, DbError> {
+    let segment = fs::read(path).map_err(|e| DbError::LoadFailed(e.to_string()))?;
+    Ok(decode_segment(&segment, f))
+}
 
-        match instruction_type {
-            CommandType::Set => map.insert(key.to_string(), val),
-            CommandType::Delete => map.insert(
-                key.to_string(),
-                format!("{}___________TOMBSTONE________________", val),
-            ),
-            _ => {
-                todo!()
-            }
-        };
+/// Cuts a segment back to `valid_len`, removing it if not even the header survived.
+fn truncate_segment(path: &str, valid_len: u64) -> Result<(), DbError> {
+    if valid_len < SEGMENT_HEADER_LEN {
+        return fs::remove_file(path).map_err(|e| DbError::LoadFailed(e.to_string()));
+    }
+
+    let file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(|e| DbError::LoadFailed(e.to_string()))?;
+    file.set_len(valid_len)
+        .map_err(|e| DbError::LoadFailed(e.to_string()))?;
+    file.sync_all()
+        .map_err(|e| DbError::LoadFailed(e.to_string()))
+}
+
+fn open_segment(file_dir: &str, first_seq: u64) -> Result<(File, u64), DbError> {
+    let path = format!("{}/{:020}{}", file_dir, first_seq, SEGMENT_SUFFIX);
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+    let mut size = file
+        .metadata()
+        .map_err(|e| DbError::WalStoreFailed(e.to_string()))?
+        .len();
+
+    if size == 0 {
+        file.write_all(&segment_header())
+            .map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+        file.sync_all()
+            .map_err(|e| DbError::WalStoreFailed(e.to_string()))?;
+        size = SEGMENT_HEADER_LEN;
+    }
+
+    Ok((file, size))
+}
+
+fn read_checkpoint(file_dir: &str) -> Result<u64, DbError> {
+    match fs::read_to_string(format!("{}/{}", file_dir, CHECKPOINT_FILE)) {
+        Ok(content) => content
+            .trim()
+            .parse::<u64>()
+            .map_err(|e| DbError::LoadFailed(format!("invalid wal checkpoint: {}", e))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(DbError::LoadFailed(e.to_string())),
     }
 }
@@ -0,0 +1,184 @@
+use crate::common::entry::Entry;
+
+/// Every segment starts with this header:
+/// - Magic (8 bytes): "MINIDWAL"
+/// - Version (1 byte)
+/// - Reserved (7 bytes)
+pub const SEGMENT_MAGIC: &[u8; 8] = b"MINIDWAL";
+pub const SEGMENT_VERSION: u8 = 1;
+pub const SEGMENT_HEADER_LEN: u64 = 16;
+
+/// Upper bound on a single record, so a corrupt length can't trigger a huge read.
+pub const MAX_RECORD_LEN: usize = 64 * 1024 * 1024;
+
+/// Bytes of a record's payload besides its key and value.
+pub const RECORD_OVERHEAD: usize = 25;
+
+const OP_SET: u8 = 1;
+const OP_DELETE: u8 = 2;
+const OP_SET_EXPIRING: u8 = 3;
+
+/// One decoded WAL record.
+pub struct WalRecord {
+    pub key: Vec<u8>,
+    pub entry: Entry,
+}
+
+/// Result of scanning a segment: how many bytes held valid records, and
+/// where the first torn or corrupt record starts, if any.
+pub struct SegmentScan {
+    pub valid_len: u64,
+    pub corrupt_at: Option<u64>,
+    /// The bad record is the last thing in the segment, as a crash in the
+    /// middle of an append leaves it. Anything else is damage to records that
+    /// were already acknowledged.
+    pub torn_tail: bool,
+}
+
+pub fn segment_header() -> [u8; SEGMENT_HEADER_LEN as usize] {
+    let mut header = [0u8; SEGMENT_HEADER_LEN as usize];
+    header[..8].copy_from_slice(SEGMENT_MAGIC);
+    header[8] = SEGMENT_VERSION;
+    header
+}
+
+/// Encodes a record as:
+/// - payload_len (u32 BE)
+/// - crc32 of payload (u32 BE)
+/// - payload:
+///   - seq (u64 BE)
+///   - op (u8): 1=SET, 2=DELETE, 3=SET with an expiry time
+///   - expires_at (u64 BE, Unix milliseconds), only for op 3
+///   - key_len (u32 BE)
+///   - key (bytes)
+///   - value_len (u32 BE), 0 for a DELETE
+///   - value (bytes)
+pub fn encode_record(key: &[u8], entry: &Entry) -> Vec<u8> {
+    let op = match entry {
+        Entry::Delete { .. } => OP_DELETE,
+        Entry::Put {
+            expires_at: None, ..
+        } => OP_SET,
+        Entry::Put {
+            expires_at: Some(_),
+            ..
+        } => OP_SET_EXPIRING,
+    };
+    let value = entry.value().unwrap_or_default();
+
+    let mut payload = Vec::with_capacity(RECORD_OVERHEAD + key.len() + value.len());
+    payload.extend_from_slice(&entry.seq().to_be_bytes());
+    payload.push(op);
+    if let Some(expires_at) = entry.expires_at() {
+        payload.extend_from_slice(&expires_at.to_be_bytes());
+    }
+    payload.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    payload.extend_from_slice(key);
+    payload.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    payload.extend_from_slice(value);
+
+    let mut record = Vec::with_capacity(8 + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+    record.extend_from_slice(&payload);
+
+    record
+}
+
+/// Decodes every record in `segment` in order, calling `f` for each, and
+/// stops at the first one that is truncated or fails its checksum.
+pub fn decode_segment(segment: &[u8], mut f: impl FnMut(WalRecord)) -> SegmentScan {
+    if segment.len() < SEGMENT_HEADER_LEN as usize
+        || &segment[..8] != SEGMENT_MAGIC
+        || segment[8] != SEGMENT_VERSION
+    {
+        // A crash while creating the segment can leave the header short
+        return SegmentScan {
+            valid_len: 0,
+            corrupt_at: Some(0),
+            torn_tail: segment.len() < SEGMENT_HEADER_LEN as usize,
+        };
+    }
+
+    let mut offset = SEGMENT_HEADER_LEN as usize;
+
+    while offset < segment.len() {
+        match decode_record(&segment[offset..]) {
+            Some((record, record_len)) => {
+                f(record);
+                offset += record_len;
+            }
+            None => {
+                return SegmentScan {
+                    valid_len: offset as u64,
+                    corrupt_at: Some(offset as u64),
+                    torn_tail: is_torn_tail(&segment[offset..]),
+                };
+            }
+        }
+    }
+
+    SegmentScan {
+        valid_len: offset as u64,
+        corrupt_at: None,
+        torn_tail: false,
+    }
+}
+
+/// Whether the bad record at the start of `rest` can be a torn append: its
+/// frame runs past the end of the file, or it and everything after it are
+/// zeros the filesystem allocated before the data reached the disk. Also
+/// used for the manifest, whose edits are framed the same way.
+pub fn is_torn_tail(rest: &[u8]) -> bool {
+    let frame_end = rest
+        .get(0..4)
+        .map(|len| 8 + u32::from_be_bytes(len.try_into().unwrap()) as usize);
+    frame_end.is_none_or(|end| end >= rest.len()) || rest.iter().all(|b| *b == 0)
+}
+
+/// Decodes the record at the start of `buf` and returns it with its encoded length.
+fn decode_record(buf: &[u8]) -> Option<(WalRecord, usize)> {
+    let payload_len = u32::from_be_bytes(buf.get(0..4)?.try_into().ok()?) as usize;
+    if payload_len > MAX_RECORD_LEN {
+        return None;
+    }
+    let crc = u32::from_be_bytes(buf.get(4..8)?.try_into().ok()?);
+    let payload = buf.get(8..8 + payload_len)?;
+
+    if crc32fast::hash(payload) != crc {
+        return None;
+    }
+
+    let seq = u64::from_be_bytes(payload.get(0..8)?.try_into().ok()?);
+    let op = *payload.get(8)?;
+
+    let mut pos = 9;
+    let mut expires_at = None;
+    if op == OP_SET_EXPIRING {
+        expires_at = Some(u64::from_be_bytes(payload.get(9..17)?.try_into().ok()?));
+        pos = 17;
+    }
+
+    let key_len = u32::from_be_bytes(payload.get(pos..pos + 4)?.try_into().ok()?) as usize;
+    let key_end = pos + 4 + key_len;
+    let key = payload.get(pos + 4..key_end)?.to_vec();
+
+    let value_len =
+        u32::from_be_bytes(payload.get(key_end..key_end + 4)?.try_into().ok()?) as usize;
+    let value_start = key_end + 4;
+    if value_start + value_len != payload_len {
+        return None;
+    }
+    let value = payload.get(value_start..)?.to_vec();
+
+    let entry = match op {
+        OP_SET | OP_SET_EXPIRING => Entry::Put {
+            seq,
+            value,
+            expires_at,
+        },
+        OP_DELETE => Entry::Delete { seq },
+        _ => return None,
+    };
+    Some((WalRecord { key, entry }, 8 + payload_len))
+}
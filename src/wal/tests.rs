@@ -0,0 +1,241 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, atomic::Ordering};
+
+use crate::{
+    common::{db_errors::DbError, entry::Entry},
+    config::{FsyncPolicy, WalConfig},
+    memtable::Memtable,
+    wal::{MAX_KEY_VALUE_LEN, Wal, list_segments, record::SEGMENT_HEADER_LEN},
+};
+
+/// Fresh WAL directory under the system temp dir.
+fn temp_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("mdb-wal-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir.to_string_lossy().into_owned()
+}
+
+fn config(segment_size_bytes: u64) -> WalConfig {
+    WalConfig {
+        fsync: FsyncPolicy::None,
+        segment_size_bytes,
+    }
+}
+
+fn write_keys(wal: &Wal, keys: std::ops::Range<u32>) {
+    for i in keys {
+        let key = format!("key{:04}", i);
+        wal.store_wal(key.as_bytes(), Some(b"value".to_vec()), None)
+            .unwrap();
+    }
+}
+
+fn replay(dir: &str) -> Result<Memtable, DbError> {
+    let wal = Wal::new(dir.to_string(), &config(1024 * 1024))?;
+    let mut memtable = Memtable::new();
+    wal.replay_into(&mut memtable)?;
+    Ok(memtable)
+}
+
+fn segments(dir: &str) -> Vec<String> {
+    list_segments(dir)
+        .unwrap()
+        .into_iter()
+        .map(|(_, path)| path)
+        .collect()
+}
+
+/// Flips one byte of `path` at `offset`.
+fn corrupt(path: &str, offset: usize) {
+    let mut bytes = fs::read(path).unwrap();
+    bytes[offset] ^= 0xff;
+    fs::write(path, bytes).unwrap();
+}
+
+#[test]
+fn torn_tail_is_truncated_and_later_appends_replay() {
+    let dir = temp_dir("torn");
+    let wal = Wal::new(dir.clone(), &config(1024 * 1024)).unwrap();
+    write_keys(&wal, 0..10);
+    drop(wal);
+
+    // Half a record, as a crash in the middle of an append leaves it
+    let segment = segments(&dir).pop().unwrap();
+    let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+    file.write_all(&[0, 0, 0, 40, 1, 2, 3]).unwrap();
+    drop(file);
+
+    let wal = Wal::new(dir.clone(), &config(1024 * 1024)).unwrap();
+    write_keys(&wal, 10..20);
+    drop(wal);
+
+    let memtable = replay(&dir).unwrap();
+    assert_eq!(memtable.data.len(), 20);
+    assert_eq!(memtable.last_seq, 20);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn bad_crc_before_other_records_fails_open() {
+    let dir = temp_dir("crc");
+    let wal = Wal::new(dir.clone(), &config(1024 * 1024)).unwrap();
+    write_keys(&wal, 0..10);
+    drop(wal);
+
+    // Inside the payload of the first record, with nine valid ones after it
+    let segment = segments(&dir).pop().unwrap();
+    corrupt(&segment, SEGMENT_HEADER_LEN as usize + 12);
+    let len = fs::metadata(&segment).unwrap().len();
+
+    assert!(matches!(replay(&dir), Err(DbError::LoadFailed(_))));
+    // Nothing was truncated, so the records after it can still be salvaged
+    assert_eq!(fs::metadata(&segment).unwrap().len(), len);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn damage_in_sealed_segment_fails_open() {
+    let dir = temp_dir("sealed");
+    // Small segments, so the records span several of them
+    let wal = Wal::new(dir.clone(), &config(128)).unwrap();
+    write_keys(&wal, 0..20);
+    drop(wal);
+
+    let all = segments(&dir);
+    assert!(all.len() > 2);
+    // The last record of a sealed segment looks like a torn tail, but only
+    // the newest segment can have one
+    let sealed = &all[0];
+    let len = fs::metadata(sealed).unwrap().len() as usize;
+    corrupt(sealed, len - 1);
+
+    assert!(matches!(replay(&dir), Err(DbError::LoadFailed(_))));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn oversized_records_are_rejected_before_they_are_logged() {
+    let dir = temp_dir("oversized");
+    let wal = Wal::new(dir.clone(), &config(1024 * 1024)).unwrap();
+    write_keys(&wal, 0..1);
+
+    let value = vec![b'x'; MAX_KEY_VALUE_LEN];
+    assert!(matches!(
+        wal.store_wal(b"big", Some(value), None),
+        Err(DbError::InvalidCommand(_))
+    ));
+    write_keys(&wal, 1..2);
+    drop(wal);
+
+    let memtable = replay(&dir).unwrap();
+    assert_eq!(memtable.data.len(), 2);
+    assert!(matches!(
+        memtable.get(b"key0001"),
+        Some(Entry::Put { seq: 2, .. })
+    ));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn one_sync_covers_every_record_appended_before_it() {
+    let dir = temp_dir("group-commit");
+    let wal = Arc::new(
+        Wal::new(
+            dir.clone(),
+            &WalConfig {
+                fsync: FsyncPolicy::Always,
+                segment_size_bytes: 1024 * 1024,
+            },
+        )
+        .unwrap(),
+    );
+    write_keys(&wal, 0..10);
+    assert_eq!(wal.synced_seq.load(Ordering::Acquire), 0);
+
+    // Waiting for the first record syncs the nine appended after it too, so
+    // their writers find them durable without an fsync of their own
+    wal.sync_to(1).await.unwrap();
+    assert_eq!(wal.synced_seq.load(Ordering::Acquire), 10);
+    wal.sync_to(10).await.unwrap();
+
+    // Concurrent writers all come back with their record synced
+    let writers: Vec<_> = (0..32)
+        .map(|i| {
+            let wal = wal.clone();
+            tokio::spawn(async move {
+                let key = format!("writer{:02}", i);
+                let seq = wal
+                    .store_wal(key.as_bytes(), Some(b"value".to_vec()), None)
+                    .unwrap()
+                    .seq();
+                wal.sync_to(seq).await.unwrap();
+                assert!(wal.synced_seq.load(Ordering::Acquire) >= seq);
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.await.unwrap();
+    }
+    assert_eq!(wal.synced_seq.load(Ordering::Acquire), 42);
+    drop(wal);
+
+    assert_eq!(replay(&dir).unwrap().data.len(), 42);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn sealed_segments_are_synced_under_every_policy() {
+    let dir = temp_dir("roll-sync");
+    // `config` uses the `none` policy
+    let wal = Wal::new(dir.clone(), &config(1024 * 1024)).unwrap();
+    write_keys(&wal, 0..5);
+    assert_eq!(wal.synced_seq.load(Ordering::Acquire), 0);
+    assert_eq!(wal.roll_segment().unwrap(), 5);
+    assert_eq!(wal.synced_seq.load(Ordering::Acquire), 5);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn restart_replays_every_segment_in_order() {
+    let dir = temp_dir("restart");
+    // Small segments, so the records span several of them
+    let wal = Wal::new(dir.clone(), &config(128)).unwrap();
+    write_keys(&wal, 0..20);
+    wal.store_wal(b"key0003", None, None).unwrap();
+    wal.store_wal(b"key0005", Some(b"newer".to_vec()), Some(u64::MAX))
+        .unwrap();
+    drop(wal);
+    assert!(segments(&dir).len() > 2);
+
+    let memtable = replay(&dir).unwrap();
+    assert_eq!(memtable.data.len(), 20);
+    assert_eq!(memtable.last_seq, 22);
+    assert!(matches!(
+        memtable.get(b"key0003"),
+        Some(Entry::Delete { seq: 21 })
+    ));
+    assert!(matches!(
+        memtable.get(b"key0005"),
+        Some(Entry::Put { seq: 22, value, expires_at: Some(u64::MAX) }) if value == b"newer"
+    ));
+
+    // Writes after the restart keep counting from the replayed ones
+    let wal = Wal::new(dir.clone(), &config(128)).unwrap();
+    assert_eq!(wal.store_wal(b"k", None, None).unwrap().seq(), 23);
+    drop(wal);
+
+    // Records at or below the checkpoint are already in SSTables
+    let wal = Wal::new(dir.clone(), &config(128)).unwrap();
+    wal.checkpoint(20).unwrap();
+    let mut memtable = Memtable::new();
+    assert_eq!(wal.replay_into(&mut memtable).unwrap(), 3);
+    drop(wal);
+
+    let _ = fs::remove_dir_all(&dir);
+}